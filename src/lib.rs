@@ -0,0 +1,4113 @@
+//! The scanning, hashing and grouping behind the fast-dedup command line. `find_duplicate_images` does
+//! a whole run over a directory without printing anything; the pieces it is made of are public too.
+
+// progress imports
+use indicatif::HumanBytes;
+
+// file system imports
+use std::fs::{self, DirEntry};
+use std::io;
+use std::path::{Path, PathBuf};
+
+// hashing imports
+use fast_dhash::Dhash;
+use image::DynamicImage;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+
+// cache imports
+use fs2::FileExt;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+// multithreading imports
+use rayon::prelude::*;
+use std::thread;
+
+// misc imports
+use regex::Regex;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// What to do when a file can't be read, decoded, copied, moved or deleted
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum OnError {
+    Skip,   // leave the file out, warn and carry on
+    Abort,  // stop the run at the first failure
+    Retry,  // retry every failure, not just transient ones, then skip
+}
+
+// One run's settings, and everything it keeps track of while it goes. Callers make one per run and pass
+// it to the work that needs it, so nothing carries over from one run to the next in the same process.
+pub struct RunContext {
+    pub retries: u32,
+    pub on_error: OnError,
+    pub verbose: bool,
+    pub with_companions: bool,
+    pub decoder_threads: usize,
+    pub cross_device_moves: bool,
+    pub trash: bool,
+    // Set by --time-budget: once it has passed no new file is started, and the ones left over are counted
+    pub deadline: Option<Instant>,
+    pub deferred: AtomicU64,
+    // Raised by the command line's Ctrl-C handler; an interrupted run stops the same way as an aborted one
+    pub interrupted: Arc<AtomicBool>,
+    // The failure that stopped the run under --on-error abort
+    aborted: OnceLock<String>,
+    pub progress_log: Option<ProgressLog>,
+    pub skip_report: Option<SkipReport>,
+    pub summary: Summary,
+    // Images that are truncated or failed to decode, never hashed and moved aside by --quarantine
+    pub damaged: Mutex<Vec<String>>,
+    // Set by --robust-match: a second hash per image path, of the centre of the image only
+    pub centre_hashes: Option<HashesByPath>,
+    // Set by --consensus: an average hash per image path, a second opinion that two images really match
+    pub average_hashes: Option<HashesByPath>,
+    // Set by --hash-bits above 64: the side of the grid a wider dHash is made from, with that hash per image
+    // path. The 64-bit dHash still decides which images are compared, the wide one then has to agree too.
+    pub wide_hashes: Option<(u32, Mutex<HashMap<String, WideHash>>)>,
+    // With --stream, the first path seen for each hash, so matches can be shown while hashing is still going
+    pub match_stream: Option<Mutex<HashMap<u64, String>>>,
+    // Set by --resume, hashes are recorded in it as they are made
+    pub checkpoint: Option<Checkpoint>,
+    pub deletion_log: Option<DeletionLog>,
+}
+
+impl Default for RunContext {
+    fn default() -> RunContext {
+        RunContext {
+            retries: 0,
+            on_error: OnError::Skip,
+            verbose: false,
+            with_companions: false,
+            decoder_threads: 1,
+            cross_device_moves: true,
+            trash: false,
+            deadline: None,
+            deferred: AtomicU64::new(0),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            aborted: OnceLock::new(),
+            progress_log: None,
+            skip_report: None,
+            summary: Summary::default(),
+            damaged: Mutex::new(vec![]),
+            centre_hashes: None,
+            average_hashes: None,
+            wide_hashes: None,
+            match_stream: None,
+            checkpoint: None,
+            deletion_log: None,
+        }
+    }
+}
+
+// How many times --on-error retry tries again when --retries isn't given
+const DEFAULT_RETRIES: u32 = 3;
+
+impl RunContext {
+    // Checked between files, so a file that was started is always finished
+    pub fn out_of_time(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    // Count a file that wasn't started because the time budget ran out
+    pub fn defer(&self) {
+        self.deferred.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    // Count a file that failed, keeping the message (which names the file) for the summary at the end,
+    // and under --on-error abort stop the run at the first one
+    pub fn file_failed(&self, why: impl Display) {
+        self.summary.errors.fetch_add(1, Ordering::Relaxed);
+        self.summary.failures.lock().unwrap().push(why.to_string());
+        if self.on_error == OnError::Abort {
+            let _ = self.aborted.set(why.to_string());
+        }
+    }
+
+    // Why the run was stopped before its end, if it was, not counting an interruption
+    pub fn abort_reason(&self) -> Option<&str> {
+        self.aborted.get().map(String::as_str)
+    }
+
+    // Checked between files like out_of_time, so a file that was started is always finished
+    fn aborted(&self) -> bool {
+        self.aborted.get().is_some() || self.interrupted()
+    }
+
+    // Run a filesystem operation, retrying transient failures up to --retries times with exponential backoff.
+    // Permanent failures (not found, permission denied, ...) are returned straight away, unless --on-error retry
+    // asks for every failure to be retried.
+    fn with_retries<T, E: Transient + Display>(
+        &self, action: &str, path: &Path, progress: &dyn Progress, mut op: impl FnMut() -> Result<T, E>
+    ) -> Result<T, E> {
+        let retry_all = self.on_error == OnError::Retry;
+        let retries = match self.retries {
+            0 if retry_all => DEFAULT_RETRIES,
+            retries => retries,
+        };
+        let mut delay = Duration::from_millis(100);
+        let mut attempt = 0;
+
+        loop {
+            match op() {
+                Err(why) if attempt < retries && (retry_all || why.is_transient()) => {
+                    attempt += 1;
+                    if self.verbose {
+                        progress.on_warning(&format!("Retrying {} '{}' ({}/{}) after error: {}", action, path.display(), attempt, retries, why));
+                    }
+                    thread::sleep(delay);
+                    delay *= 2;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    // Begin a new phase of `total` steps (0 if unknown), in the progress log if there is one
+    pub fn progress_start(&self, name: &'static str, total: u64, progress: &dyn Progress) {
+        progress.on_phase(name);
+        if let Some(log) = &self.progress_log {
+            let mut phase = log.phase.lock().unwrap();
+            *phase = PhaseProgress { name, current: 0, total, started: Instant::now(), last_event: None };
+            log.emit(&mut phase);
+        }
+    }
+
+    // Record `steps` more units of work done in the current phase
+    fn progress_advance(&self, steps: u64) {
+        if let Some(log) = &self.progress_log {
+            let mut phase = log.phase.lock().unwrap();
+            phase.current += steps;
+            let due = phase.last_event.is_none_or(|last| last.elapsed() >= PROGRESS_EVENT_INTERVAL);
+            if due || phase.current == phase.total {
+                log.emit(&mut phase);
+            }
+        }
+    }
+
+    // Close the current phase, always emitting its final state
+    pub fn progress_finish(&self, current: u64) {
+        if let Some(log) = &self.progress_log {
+            let mut phase = log.phase.lock().unwrap();
+            phase.current = current;
+            phase.total = phase.total.max(current);
+            log.emit(&mut phase);
+        }
+    }
+
+    // Note a skipped file in the --skipped-report manifest, if there is one
+    pub fn record_skip(&self, reason: SkipReason, path: &Path, detail: impl Display, progress: &dyn Progress) {
+        let detail = detail.to_string();
+        if self.verbose {
+            match detail.is_empty() {
+                true => progress.on_message(&format!("Skipped '{}' ({})", path.display(), reason.code())),
+                false => progress.on_message(&format!("Skipped '{}' ({}: {})", path.display(), reason.code(), detail)),
+            }
+        }
+        if let Some(report) = &self.skip_report {
+            report.entries.lock().unwrap().push((reason, detail, path.display().to_string()));
+        }
+    }
+}
+
+// Errors that may go away if the operation is simply tried again, e.g. on a flaky network share
+trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for io::Error {
+    fn is_transient(&self) -> bool {
+        matches!(self.kind(), io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+    }
+}
+
+impl Transient for image::ImageError {
+    fn is_transient(&self) -> bool {
+        match self {
+            image::ImageError::IoError(why) => why.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+// Machine readable progress, written as one JSON object per line for GUIs and job monitors
+pub struct ProgressLog {
+    writer: Mutex<BufWriter<fs::File>>,
+    phase: Mutex<PhaseProgress>,
+}
+
+struct PhaseProgress {
+    name: &'static str,
+    current: u64,
+    total: u64,
+    started: Instant,
+    last_event: Option<Instant>,
+}
+
+// Progress events within a phase are emitted at most this often, except for the final one
+const PROGRESS_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+impl ProgressLog {
+    pub fn open(file: &Path) -> io::Result<ProgressLog> {
+        Ok(ProgressLog {
+            writer: Mutex::new(BufWriter::new(fs::File::create(file)?)),
+            phase: Mutex::new(PhaseProgress { name: "starting", current: 0, total: 0, started: Instant::now(), last_event: None }),
+        })
+    }
+
+    fn emit(&self, phase: &mut PhaseProgress) {
+        let elapsed = phase.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {phase.current as f64 / elapsed} else {0.0};
+        let mut writer = self.writer.lock().unwrap();
+        // A broken progress log shouldn't interrupt the actual work, so write errors are ignored
+        let _ = writeln!(
+            writer, r#"{{"phase":"{}","current":{},"total":{},"rate":{:.2},"elapsed":{:.3}}}"#,
+            phase.name, phase.current, phase.total, rate, elapsed
+        );
+        let _ = writer.flush();
+        phase.last_event = Some(Instant::now());
+    }
+}
+
+// Callbacks through which the scan, hashing and action phases report what they are doing, so they can
+// drive any UI. Every method does nothing by default; `worker` numbers the hashing threads from 0.
+pub trait Progress: Sync {
+    fn on_phase(&self, _name: &'static str) {}
+    fn on_scan_file(&self, _path: &Path) {}
+    fn on_hash_start(&self, _worker: usize, _workers: usize, _total_bytes: u64) {}
+    fn on_hash_progress(&self, _worker: usize, _path: &str, _bytes: u64) {}
+    fn on_hash_done(&self, _worker: usize) {}
+    fn on_group_found(&self, _group: &DuplicateGroup<ImageInfo>) {}
+    fn on_action_start(&self, _message: &str, _total: u64) {}
+    fn on_action_progress(&self, _path: &str) {}
+    fn on_action_done(&self, _message: &str) {}
+    // Lines for the user, which a UI may need to print around its own output
+    fn on_message(&self, _line: &str) {}
+    fn on_warning(&self, _line: &str) {}
+}
+
+// Reports nothing, for work that runs behind the scenes
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+// Why a file was left out of the comparison, written to the --skipped-report manifest
+#[derive(Clone, Copy)]
+pub enum SkipReason {
+    UnreadableDir,
+    NoModifiedTime,
+    OutsideAgeWindow,
+    BelowMinSize,
+    HardLink,
+    NonUtf8Path,
+    UnreadableArchive,
+    DecodeFailed,
+    NoMetadata,
+    BelowMinGroupSize,
+    AboveMaxGroupSize,
+    Truncated,
+    NotFound,
+    NotAnImage,
+}
+
+impl SkipReason {
+    fn code(&self) -> &'static str {
+        match self {
+            SkipReason::UnreadableDir => "unreadable-dir",
+            SkipReason::NoModifiedTime => "no-modified-time",
+            SkipReason::OutsideAgeWindow => "outside-age-window",
+            SkipReason::BelowMinSize => "below-min-size",
+            SkipReason::HardLink => "hard-link",
+            SkipReason::NonUtf8Path => "non-utf8-path",
+            SkipReason::UnreadableArchive => "unreadable-archive",
+            SkipReason::DecodeFailed => "decode-failed",
+            SkipReason::NoMetadata => "no-metadata",
+            SkipReason::BelowMinGroupSize => "below-min-group-size",
+            SkipReason::AboveMaxGroupSize => "above-max-group-size",
+            SkipReason::Truncated => "truncated",
+            SkipReason::NotFound => "not-found",
+            SkipReason::NotAnImage => "not-an-image",
+        }
+    }
+}
+
+// Every skipped file with its reason, collected from all stages and written out once the run ends
+pub struct SkipReport {
+    writer: Mutex<BufWriter<fs::File>>,
+    entries: Mutex<Vec<(SkipReason, String, String)>>,
+}
+
+impl SkipReport {
+    const HEADER: &'static str = "# fast-dedup skipped files v1";
+
+    // The file is created up front so a bad path fails before any work is done
+    pub fn open(file: &Path) -> io::Result<SkipReport> {
+        Ok(SkipReport {
+            writer: Mutex::new(BufWriter::new(fs::File::create(file)?)),
+            entries: Mutex::new(vec![]),
+        })
+    }
+
+    // One line per file: reason code, detail (often an error message, may be empty) and path
+    pub fn save(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(writer, "{}", SkipReport::HEADER)?;
+        for (reason, detail, path) in self.entries.lock().unwrap().iter() {
+            writeln!(writer, "{}\t{}\t{}", reason.code(), detail.replace(['\t', '\n'], " "), path)?;
+        }
+        writer.flush()
+    }
+}
+
+// Running totals for the --notify-line summary, updated wherever the counted thing happens
+#[derive(Default)]
+pub struct Summary {
+    pub scanned: AtomicU64,
+    pub duplicates: AtomicU64,
+    pub freed: AtomicU64,  // bytes
+    pub errors: AtomicU64,
+    pub failures: Mutex<Vec<String>>,
+}
+
+impl Summary {
+    // Scripts parse this line, so the prefix, field names and their order must never change
+    pub fn line(&self) -> String {
+        format!(
+            "fast-dedup: scanned={} duplicates={} freed={} errors={}",
+            self.scanned.load(Ordering::Relaxed), self.duplicates.load(Ordering::Relaxed),
+            self.freed.load(Ordering::Relaxed), self.errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// Width and height of a decoded image
+pub type Dimensions = (u32, u32);
+
+// An image path with its hash and dimensions, as hashing hands them back
+type HashedImage = (String, Dhash, Dimensions);
+
+// An image path along with its hash, the dimensions it had when it was hashed and its size on disk
+#[derive(Clone)]
+pub struct ImageInfo {
+    pub path: String,
+    pub hash: u64,
+    pub dimensions: Dimensions,
+    pub size: u64,
+    pub root: usize,  // index of the scanned directory the image was found under
+    pub jpeg_quality: Option<u8>,  // estimated quality setting (1 to 100) of a JPEG, None for other formats
+    pub metadata: u32,  // how much annotation the image carries, see metadata_richness; 0 unless --prefer-metadata
+    pub modified: Option<SystemTime>,  // the file's modification time, only read for --keep-oldest and --keep-newest
+}
+
+impl ImageInfo {
+    fn area(&self) -> u64 {
+        self.dimensions.0 as u64 * self.dimensions.1 as u64
+    }
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.dimensions.0 as f64 / self.dimensions.1.max(1) as f64
+    }
+}
+
+// A discarded duplicate much bigger on disk than the kept original, despite having fewer pixels,
+// is often the better copy (the original may just be a large but heavily compressed re-save)
+pub fn is_suspicious_discard(original: &ImageInfo, duplicate: &ImageInfo) -> bool {
+    duplicate.area() < original.area() && duplicate.size > 2 * original.size
+}
+
+// The primary rule used to decide which image of a group is kept
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Prefer {
+    Resolution,
+    #[value(name = "filesize")]
+    FileSize,
+    Both,  // resolution, with the larger file winning between equal pixel areas
+    Ssim,  // the member closest to the group's median image, see elect_by_ssim; compares like Resolution
+}
+
+// Which modification time wins between images that tie on everything but their path
+#[derive(Clone, Copy, PartialEq)]
+pub enum KeepAge {
+    Oldest,
+    Newest,
+}
+
+// The outcome of comparing two images, along with a human readable explanation of the winner
+pub struct Verdict {
+    better: bool,
+    reason: String,
+}
+
+// Everything that goes into deciding which image of a group is kept
+pub struct QualityRules {
+    pub prefer: Prefer,
+    pub preferred_name: Option<Regex>,  // files whose name matches always beat files whose name doesn't
+    pub remove_from: Option<usize>,  // images under this root always lose to images under any other
+    pub reference: Option<usize>,  // images under this root always beat images under any other
+    pub keep_age: Option<KeepAge>,  // the last tie-break before the path
+}
+
+impl QualityRules {
+    // What ranks an image above any difference in quality: being in the --reference library, then a name
+    // matching --prefer-name, then being outside the --remove-from directory. Higher wins.
+    fn standing(&self, image: &ImageInfo) -> (bool, bool, bool) {
+        (self.reference == Some(image.root), self.name_preferred(image), self.remove_from != Some(image.root))
+    }
+
+    fn name_preferred(&self, image: &ImageInfo) -> bool {
+        let name = Path::new(&image.path).file_name().and_then(|name| name.to_str());
+        match (&self.preferred_name, name) {
+            (Some(pattern), Some(name)) => pattern.is_match(name),
+            _ => false,
+        }
+    }
+}
+
+// Implement partial ordering for images. None means the two can't be compared.
+pub trait IsBetterQual {
+    fn partial_cmp(&self, other: &Self, rules: &QualityRules) -> Option<Verdict>;
+    // The file compared, for messages about it
+    fn path(&self) -> &str;
+}
+impl IsBetterQual for ImageInfo {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn partial_cmp(&self, other: &Self, rules: &QualityRules) -> Option<Verdict> {
+        let (self_reference, other_reference) = (rules.reference == Some(self.root), rules.reference == Some(other.root));
+        if self_reference != other_reference {
+            return Some(Verdict { better: self_reference, reason: String::from("in the --reference library") });
+        }
+
+        let (self_named, other_named) = (rules.name_preferred(self), rules.name_preferred(other));
+        if self_named != other_named {
+            return Some(Verdict { better: self_named, reason: String::from("name matches --prefer-name") });
+        }
+
+        let (self_removable, other_removable) = (rules.remove_from == Some(self.root), rules.remove_from == Some(other.root));
+        if self_removable != other_removable {
+            return Some(Verdict { better: other_removable, reason: String::from("outside the --remove-from directory") });
+        }
+
+        // The preferred measure decides first, then the tie-breaks in order, so the same group always
+        // elects the same original no matter the order its members were found in
+        let by_area = ("area", self.area().cmp(&other.area()));
+        let by_width = ("width", self.dimensions.0.cmp(&other.dimensions.0));
+        // Quality estimates only mean something between two JPEGs, otherwise the next tie-break decides
+        let by_quality = ("quality", match (self.jpeg_quality, other.jpeg_quality) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => std::cmp::Ordering::Equal,
+        });
+        let by_metadata = ("metadata", self.metadata.cmp(&other.metadata));
+        let by_size = ("size", self.size.cmp(&other.size));
+        let by_age = ("modified", match (rules.keep_age, self.modified, other.modified) {
+            (Some(KeepAge::Oldest), Some(a), Some(b)) => b.cmp(&a),
+            (Some(KeepAge::Newest), Some(a), Some(b)) => a.cmp(&b),
+            _ => std::cmp::Ordering::Equal,
+        });
+        let by_path = ("path", other.path.cmp(&self.path));  // the alphabetically first path wins
+        let prefer = if rules.prefer == Prefer::Ssim {Prefer::Resolution} else {rules.prefer};
+        let precedence = match prefer {
+            Prefer::Resolution | Prefer::Ssim => [by_area, by_width, by_quality, by_metadata, by_size, by_age, by_path],
+            Prefer::FileSize => [by_size, by_area, by_width, by_quality, by_metadata, by_age, by_path],
+            Prefer::Both => [by_area, by_size, by_width, by_quality, by_metadata, by_age, by_path],
+        };
+        let (decider, ordering) = precedence.into_iter()
+            .find(|(_, ordering)| ordering.is_ne())
+            .unwrap_or(("path", std::cmp::Ordering::Equal));
+        let better = ordering.is_gt();
+        let winner = if better {self} else {other};
+        let (width, height) = winner.dimensions;
+        let file_size = HumanBytes(winner.size);
+        let quality = winner.jpeg_quality.unwrap_or(0);
+
+        let reason = match (prefer, decider) {
+            (Prefer::Resolution, "area") => format!("largest dimensions {}x{}", width, height),
+            (Prefer::Resolution, "width") => format!("tied on pixel area, widest at {}x{}", width, height),
+            (Prefer::Resolution, "quality") => format!("tied on dimensions {}x{}, highest JPEG quality {}", width, height, quality),
+            (Prefer::Resolution, "metadata") => format!("tied on dimensions {}x{}, richest metadata", width, height),
+            (Prefer::Resolution, "size") => format!("tied on dimensions {}x{}, largest file {}", width, height, file_size),
+            (Prefer::FileSize, "size") => format!("largest file {}", file_size),
+            (Prefer::FileSize, "area") => format!("tied on file size {}, largest dimensions {}x{}", file_size, width, height),
+            (Prefer::FileSize, "width") => format!("tied on file size {} and pixel area, widest at {}x{}", file_size, width, height),
+            (Prefer::FileSize, "quality") => format!("tied on file size {} and dimensions, highest JPEG quality {}", file_size, quality),
+            (Prefer::FileSize, "metadata") => format!("tied on file size {} and dimensions, richest metadata", file_size),
+            (Prefer::Both, "area") => format!("largest dimensions {}x{}", width, height),
+            (Prefer::Both, "size") => format!("tied on pixel area, largest file {}", file_size),
+            (Prefer::Both, "width") => format!("tied on pixel area and file size {}, widest at {}x{}", file_size, width, height),
+            (Prefer::Both, "quality") => format!("tied on dimensions {}x{} and file size, highest JPEG quality {}", width, height, quality),
+            (Prefer::Both, "metadata") => format!("tied on dimensions {}x{} and file size, richest metadata", width, height),
+            (_, "modified") => format!(
+                "tied on dimensions {}x{} and file size {}, {} modified",
+                width, height, file_size, if rules.keep_age == Some(KeepAge::Oldest) {"first"} else {"last"}
+            ),
+            _ => format!("tied on dimensions {}x{} and file size {}, first path alphabetically", width, height, file_size),
+        };
+
+        Some(Verdict { better, reason })
+    }
+}
+
+// Extensions scanned without --all-formats, in lowercase. Files are matched case-insensitively, so "Jpg"
+// or "pNG" aren't missed. Add one here to scan another format by default
+const DEFAULT_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "webp", "gif", "bmp", "tif", "tiff"];
+
+// Check if a given path points to an image file. With `all_formats`, any extension the image crate
+// can decode is accepted and files that turn out not to be images are rejected when decoding fails.
+fn is_image(path: &Path, all_formats: bool) -> bool {
+    match path.extension() {
+        Some(ext) if all_formats => image::ImageFormat::from_extension(ext)
+            .is_some_and(|format| format.can_read() && format.reading_enabled()),
+        Some(ext) => ext.to_str()
+            .is_some_and(|ext| DEFAULT_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())),
+        None => false
+    }
+}
+
+// Members of zip archives are addressed as "<archive>.zip!/<name inside the archive>"
+const ARCHIVE_SEPARATOR: &str = "!/";
+
+// Split an archive member path into the archive's path and the member's name, None for plain files
+pub fn archive_member(path: &str) -> Option<(&str, &str)> {
+    if !cfg!(feature = "zip") {return None;}
+    let (archive, member) = path.split_once(ARCHIVE_SEPARATOR)?;
+    archive.to_ascii_lowercase().ends_with(".zip").then_some((archive, member))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+// List the images inside a zip archive as (member path, uncompressed size) pairs
+#[cfg(feature = "zip")]
+pub fn list_archive(archive: &str, all_formats: bool) -> io::Result<Vec<(String, u64)>> {
+    let mut zip = zip::ZipArchive::new(fs::File::open(archive)?)?;
+    let mut members = vec![];
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        if file.is_file() && is_image(Path::new(file.name()), all_formats) {
+            members.push((format!("{}{}{}", archive, ARCHIVE_SEPARATOR, file.name()), file.size()));
+        }
+    }
+    Ok(members)
+}
+
+#[cfg(not(feature = "zip"))]
+pub fn list_archive(_archive: &str, _all_formats: bool) -> io::Result<Vec<(String, u64)>> {
+    Ok(vec![])
+}
+
+// Read a member of a zip archive into memory, nothing is ever extracted to disk
+#[cfg(feature = "zip")]
+fn read_archive_member(archive: &str, member: &str) -> io::Result<Vec<u8>> {
+    let mut zip = zip::ZipArchive::new(fs::File::open(archive)?)?;
+    let mut file = zip.by_name(member)?;
+    let mut bytes = Vec::with_capacity(file.size() as usize);
+    io::Read::read_to_end(&mut file, &mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(not(feature = "zip"))]
+fn read_archive_member(_archive: &str, _member: &str) -> io::Result<Vec<u8>> {
+    Err(io::Error::other("built without zip archive support"))
+}
+
+#[cfg(feature = "zip")]
+fn archive_member_size(archive: &str, member: &str) -> io::Result<u64> {
+    let mut zip = zip::ZipArchive::new(fs::File::open(archive)?)?;
+    let size = zip.by_name(member)?.size();
+    Ok(size)
+}
+
+#[cfg(not(feature = "zip"))]
+fn archive_member_size(_archive: &str, _member: &str) -> io::Result<u64> {
+    Err(io::Error::other("built without zip archive support"))
+}
+
+// Size in bytes of an image, whether it's a plain file or the uncompressed size of an archive member
+pub fn image_size(path: &str) -> io::Result<u64> {
+    match archive_member(path) {
+        Some((archive, member)) => archive_member_size(archive, member),
+        None => fs::metadata(path).map(|meta| meta.len()),
+    }
+}
+
+// On Windows, turn a path into its extended-length form (`\\?\C:\...` or `\\?\UNC\server\share\...`)
+// so deep trees past MAX_PATH and network shares can still be read, copied and deleted
+#[cfg(windows)]
+pub fn extended_path(path: &Path) -> PathBuf {
+    if path.as_os_str().to_string_lossy().starts_with(r"\\?\") {
+        return path.to_path_buf();
+    }
+
+    // canonicalize already produces the verbatim prefix, including the UNC form for shares
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(not(windows))]
+pub fn extended_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+// Settings that control which files the directory scan picks up
+pub struct ScanOptions {
+    pub all_formats: bool,
+    pub archives: bool,  // also pick up .zip files, to be listed and hashed as virtual directories
+    pub excluded: Vec<PathBuf>,  // canonical paths of output directories inside a scan root, never descended into
+    pub skip_marked: bool,  // leave out directories holding an OUTPUT_MARKER
+    pub exclude: Vec<PathGlob>,  // files and directories never scanned, see PathGlob
+    pub include: Vec<PathGlob>,  // when given, only files matching one of these are scanned
+    pub max_depth: Option<usize>,  // folders below this many levels under the root are left out, 0 scans only the root
+    pub follow_symlinks: bool,  // descend into symlinked directories, each real directory once
+    pub respect_ignore: bool,  // leave out what .gitignore, .ignore and the global git excludes do, and hidden files
+}
+
+// A shell style pattern for --exclude and --include, matched against paths relative to the scanned
+// directory with / between their parts. `*` and `?` stay within one part, `**` spans any number of them,
+// `[...]` is a set of characters. A pattern without a / matches the name of a file or directory at any depth.
+#[derive(Clone)]
+pub struct PathGlob {
+    regex: Regex,
+    anywhere: bool,
+}
+
+impl PathGlob {
+    fn matches(&self, relative: &Path) -> bool {
+        if self.anywhere {
+            relative.file_name().is_some_and(|name| self.regex.is_match(&name.to_string_lossy()))
+        } else {
+            let parts: Vec<_> = relative.components().map(|part| part.as_os_str().to_string_lossy()).collect();
+            self.regex.is_match(&parts.join("/"))
+        }
+    }
+}
+
+// Parse a --exclude or --include pattern, see PathGlob
+pub fn parse_glob(arg: &str) -> Result<PathGlob, String> {
+    let pattern = arg.trim_end_matches('/');
+    if pattern.is_empty() {return Err(format!("'{}' is not a pattern, it matches nothing", arg));}
+    let mut regex = String::from("^");
+    let mut chars = pattern.trim_start_matches("./").chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // "**/" may also stand for no directories at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let mut set = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') if !set.is_empty() && set != "^" => break,
+                        Some('!') if set.is_empty() => set.push('^'),
+                        // Special inside a regex class but not in a glob one
+                        Some(c) if "\\[&~".contains(c) => {
+                            set.push('\\');
+                            set.push(c);
+                        },
+                        Some(c) => set.push(c),
+                        None => return Err(format!("'{}' has a '[' without a matching ']'", arg)),
+                    }
+                }
+                regex.push_str(&format!("[{}]", set));
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    let regex = Regex::new(&regex).map_err(|why| format!("'{}' is not a valid pattern: {}", arg, why))?;
+    Ok(PathGlob { regex, anywhere: !pattern.contains('/') })
+}
+
+// One line of an ignore file
+struct IgnoreRule {
+    glob: PathGlob,
+    negated: bool,  // a line starting with !, which takes back what earlier lines left out
+    dir_only: bool,  // a line ending in /, which only matches directories
+}
+
+// Parse a line of a .gitignore or .ignore file. A pattern with a / other than at its end is anchored to
+// the file's directory, one without matches the name of a file or directory at any depth below it.
+fn parse_ignore_line(line: &str) -> Option<IgnoreRule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {return None;}
+    let (negated, pattern) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    // A leading \ lets a pattern start with # or !
+    let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+    let dir_only = pattern.ends_with('/');
+    let pattern = pattern.trim_end_matches('/');
+    let mut glob = parse_glob(pattern.trim_start_matches('/')).ok()?;
+    glob.anywhere = !pattern.contains('/');
+    Some(IgnoreRule { glob, negated, dir_only })
+}
+
+// The rules of the ignore files in one directory, matched against paths relative to it
+struct IgnoreFile {
+    dir: PathBuf,
+    rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreFile {
+    // The rules of those of `files` that exist, the later files' taking precedence, or None if there are none
+    fn read(dir: &Path, files: &[PathBuf]) -> Option<Arc<IgnoreFile>> {
+        let rules: Vec<IgnoreRule> = files.iter()
+            .filter_map(|file| fs::read_to_string(file).ok())
+            .flat_map(|text| text.lines().filter_map(parse_ignore_line).collect::<Vec<_>>())
+            .collect();
+        (!rules.is_empty()).then(|| Arc::new(IgnoreFile { dir: dir.to_path_buf(), rules }))
+    }
+
+    // Whether the last rule that matches `path` leaves it out, None if no rule does
+    fn verdict(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.dir).ok()?;
+        self.rules.iter().rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.glob.matches(relative))
+            .map(|rule| !rule.negated)
+    }
+}
+
+// Whether the ignore files in effect leave `path` out, the innermost directory's deciding first
+fn is_ignored(ignores: &[Arc<IgnoreFile>], path: &Path, is_dir: bool) -> bool {
+    ignores.iter().rev().find_map(|file| file.verdict(path, is_dir)).unwrap_or(false)
+}
+
+// The ignore files of a directory, .ignore taking precedence over .gitignore
+fn ignore_files_of(dir: &Path) -> [PathBuf; 2] {
+    [dir.join(".gitignore"), dir.join(".ignore")]
+}
+
+// Git's global excludes file: core.excludesFile from ~/.gitconfig, or else its default ~/.config/git/ignore
+fn global_excludes_file() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(PathBuf::from)?;
+    let configured = fs::read_to_string(home.join(".gitconfig")).ok().and_then(|config| config.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("excludesfile"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string()));
+    Some(match configured {
+        Some(file) => file.strip_prefix("~/").map_or_else(|| PathBuf::from(&file), |rest| home.join(rest)),
+        None => std::env::var_os("XDG_CONFIG_HOME").map_or_else(|| home.join(".config"), PathBuf::from).join("git/ignore"),
+    })
+}
+
+// The ignore files in effect at a scan root, outermost first: the global excludes, and if the root is in a
+// git repository, its .git/info/exclude and the ignore files of its directories above the root
+fn ignores_above(root: &Path) -> Vec<Arc<IgnoreFile>> {
+    let mut ignores: Vec<Arc<IgnoreFile>> = global_excludes_file().and_then(|file| IgnoreFile::read(root, &[file])).into_iter().collect();
+    if let Some(repo) = root.ancestors().find(|dir| dir.join(".git").exists()) {
+        ignores.extend(IgnoreFile::read(repo, &[repo.join(".git").join("info").join("exclude")]));
+        let above: Vec<&Path> = root.ancestors().skip(1).collect();
+        let inside = above.iter().position(|dir| *dir == repo).map_or(0, |i| i + 1);
+        ignores.extend(above[..inside].iter().rev().filter_map(|dir| IgnoreFile::read(dir, &ignore_files_of(dir))));
+    }
+    ignores
+}
+
+// Left in every directory this tool writes images into, so later scans recognize and skip it
+pub const OUTPUT_MARKER: &str = ".fast-dedup-output";
+
+// Mark `dir` as output, unless it already is. Failing only costs later runs the exclusion, so it warns.
+pub fn mark_output_dir(dir: &Path, progress: &dyn Progress) {
+    let marker = dir.join(OUTPUT_MARKER);
+    if marker.exists() {return;}
+    let note = "This directory holds output of fast-dedup. Scans skip it, unless run with --scan-outputs.\n";
+    if let Err(why) = fs::write(&marker, note) {
+        progress.on_warning(&format!("could not mark '{}' as output, later runs may scan it: {}", dir.display(), why));
+    }
+}
+
+// The images named in a list of paths, one per line as `find` prints them, with their sizes, for
+// scanning without a directory walk. Relative paths are taken from the current directory. Entries that
+// don't exist or aren't a supported image are reported and left out, and repeated ones are taken once.
+pub fn get_images_in_list(
+    list: impl BufRead, all_formats: bool, context: &RunContext, progress: &dyn Progress
+) -> io::Result<Vec<(String, u64)>> {
+    let mut seen = HashSet::new();
+    let mut images = vec![];
+    for line in list.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {continue;}
+        let path = extended_path(&std::path::absolute(line)?);
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(why) => {
+                progress.on_warning(&format!("skipping '{}' in the file list, it can't be read: {}", line, why));
+                context.record_skip(SkipReason::NotFound, &path, why, progress);
+                continue;
+            },
+        };
+        if meta.is_dir() || !is_image(&path, all_formats) {
+            progress.on_warning(&format!("skipping '{}' in the file list, it is not a supported image file", line));
+            context.record_skip(SkipReason::NotAnImage, &path, "", progress);
+            continue;
+        }
+        let Some(utf8_path) = path.to_str() else {
+            progress.on_warning(&format!("skipping '{}' in the file list, it is not a valid UTF-8 path", path.display()));
+            context.record_skip(SkipReason::NonUtf8Path, &path, "", progress);
+            continue;
+        };
+        if seen.insert(utf8_path.to_string()) {
+            progress.on_scan_file(&path);
+            images.push((utf8_path.to_string(), meta.len()));
+        }
+    }
+    Ok(images)
+}
+
+// Index the root directory for all image files
+pub fn get_images_in_dir(dir: &Path, options: &ScanOptions, context: &RunContext, progress: &dyn Progress) -> io::Result<Vec<DirEntry>> {
+    let ignores = if options.respect_ignore {ignores_above(dir)} else {vec![]};
+    images_below(dir, dir, 0, &ignores, &Mutex::new(HashSet::new()), options, context, progress)
+}
+
+// The images in `dir`, `depth` levels below `root`. With --follow-symlinks, `visited` holds the real
+// directories scanned so far, so a link back up the tree or a second link to one is only scanned once.
+// With --respect-ignore, `ignores` are the ignore files in effect above `dir`.
+#[allow(clippy::too_many_arguments)]
+fn images_below(
+    dir: &Path, root: &Path, depth: usize, ignores: &[Arc<IgnoreFile>], visited: &Mutex<HashSet<PathBuf>>, options: &ScanOptions,
+    context: &RunContext, progress: &dyn Progress,
+) -> io::Result<Vec<DirEntry>> {
+    let mut image_paths: Vec<DirEntry> = vec![];
+    if options.follow_symlinks && !visited.lock().unwrap().insert(fs::canonicalize(dir)?) {
+        if context.verbose {
+            progress.on_message(&format!("Not scanning '{}' again, it was reached through another path", dir.display()));
+        }
+        return Ok(image_paths);
+    }
+    let mut ignores = ignores.to_vec();
+    if options.respect_ignore {
+        ignores.extend(IgnoreFile::read(dir, &ignore_files_of(dir)));
+    }
+    let mut sub_dirs: Vec<PathBuf> = vec![];
+    if dir.is_dir() {
+        for entry in context.with_retries("reading directory", dir, progress, || fs::read_dir(dir))? {
+            let entry = entry?;
+            let path = entry.path();
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            // An excluded directory isn't even read
+            if options.exclude.iter().any(|glob| glob.matches(relative)) {continue;}
+            if options.respect_ignore {
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if hidden || is_ignored(&ignores, &path, path.is_dir()) {
+                    if context.verbose && path.is_dir() {
+                        let why = if hidden {"it is hidden"} else {"an ignore file leaves it out"};
+                        progress.on_message(&format!("Not scanning '{}', {}", path.display(), why));
+                    }
+                    continue;
+                }
+            }
+            if path.is_dir() {
+                if !options.follow_symlinks && entry.file_type().is_ok_and(|kind| kind.is_symlink()) {
+                    // It can point back up the tree, or at a directory scanned anyway
+                    if context.verbose {
+                        progress.on_message(&format!("Not following the symlinked directory '{}'", path.display()));
+                    }
+                } else if options.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+                    if context.verbose {
+                        progress.on_message(&format!("Not scanning '{}', it is deeper than --max-depth", path.display()));
+                    }
+                } else if options.skip_marked && path.join(OUTPUT_MARKER).is_file() {
+                    progress.on_message(&format!("Not scanning '{}', it holds output of an earlier run", path.display()));
+                } else if options.excluded.is_empty() || !fs::canonicalize(&path).is_ok_and(|dir| options.excluded.contains(&dir)) {
+                    sub_dirs.push(path);
+                }
+            } else if (is_image(&path, options.all_formats) || (options.archives && is_archive(&path)))
+                && (options.include.is_empty() || options.include.iter().any(|glob| glob.matches(relative))) {
+                progress.on_scan_file(&path);
+                image_paths.push(entry)
+            }
+        }
+    }
+
+    // Walk subdirectories concurrently, on slow or network storage most of the time is spent waiting on readdir
+    let nested: Vec<Vec<DirEntry>> = sub_dirs.par_iter()
+        .map(|path| match images_below(path, root, depth + 1, &ignores, visited, options, context, progress) {
+            Err(why) => {
+                let message = format!("could not read directory '{}': {}", path.display(), why);
+                progress.on_warning(&message);
+                context.file_failed(message);
+                context.record_skip(SkipReason::UnreadableDir, path, why, progress);
+                vec![]
+            },
+            Ok(paths) => paths,
+        })
+        .collect();
+    image_paths.extend(nested.into_iter().flatten());
+
+    Ok(image_paths)
+}
+
+// Keep only the images last modified inside the (optional) time window
+pub fn filter_by_age(
+    images: Vec<DirEntry>, older_than: Option<SystemTime>, newer_than: Option<SystemTime>, context: &RunContext, progress: &dyn Progress
+) -> Vec<DirEntry> {
+    if older_than.is_none() && newer_than.is_none() {return images;}
+
+    let mut kept = vec![];
+    for entry in images {
+        let modified = match entry.metadata().and_then(|meta| meta.modified()) {
+            Ok(time) => time,
+            Err(why) => {
+                progress.on_warning(&format!("excluding '{}', could not read its modification time: {}", entry.path().display(), why));
+                context.file_failed(format!("could not read the modification time of '{}': {}", entry.path().display(), why));
+                context.record_skip(SkipReason::NoModifiedTime, &entry.path(), why, progress);
+                continue;
+            }
+        };
+
+        if older_than.is_some_and(|bound| modified >= bound) || newer_than.is_some_and(|bound| modified <= bound) {
+            context.record_skip(SkipReason::OutsideAgeWindow, &entry.path(), "", progress);
+            continue;
+        }
+        kept.push(entry);
+    }
+
+    kept
+}
+
+// Leave out the images smaller than `min_size` bytes, such as icons, which are never hashed so they can't
+// match each other. Returns the images kept and how many were left out.
+pub fn filter_by_size(images: Vec<DirEntry>, min_size: u64, context: &RunContext, progress: &dyn Progress) -> (Vec<DirEntry>, usize) {
+    if min_size == 0 {return (images, 0);}
+
+    let (kept, small): (Vec<DirEntry>, Vec<DirEntry>) = images.into_iter()
+        .partition(|entry| fs::metadata(entry.path()).map_or(true, |meta| meta.len() >= min_size));
+    for entry in &small {
+        context.record_skip(SkipReason::BelowMinSize, &entry.path(), "", progress);
+    }
+
+    (kept, small.len())
+}
+
+// A (device, file index) pair that is the same for every hard link to a file, and every symlink to it
+#[cfg(unix)]
+fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(windows)]
+fn file_identity(path: &Path) -> Option<(u64, u64)> {
+    let handle = winapi_util::Handle::from_path_any(path).ok()?;
+    let info = winapi_util::file::information(&handle).ok()?;
+    Some((info.volume_serial_number(), info.file_index()))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn file_identity(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+// Hard links to the same file are one logical image, so only the first path found for each is kept.
+// Without this, a tree deduplicated by hardlinking would have its links flagged as duplicates again.
+pub fn collapse_hard_links(images: Vec<DirEntry>, context: &RunContext, progress: &dyn Progress) -> (Vec<DirEntry>, usize) {
+    let mut seen = HashSet::new();
+    let mut kept = vec![];
+    let mut collapsed = 0;
+
+    for entry in images {
+        match file_identity(&entry.path()) {
+            Some(identity) if !seen.insert(identity) => {
+                context.record_skip(SkipReason::HardLink, &entry.path(), "", progress);
+                collapsed += 1;
+            },
+            _ => kept.push(entry),
+        }
+    }
+
+    (kept, collapsed)
+}
+
+// The canonical text form of a hash is 16 lowercase hex digits, most significant bit first (the u64 in
+// big-endian order), so it reads the same on every platform. Caches and snapshots store hashes this way.
+pub fn hash_to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+fn hash_from_hex(hex: &str) -> Result<u64, String> {
+    if hex.len() != 16 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a hash, expected 16 hex digits", hex));
+    }
+    u64::from_str_radix(hex, 16).map_err(|why| why.to_string())
+}
+
+// Number of differing bits between two hex encoded hashes, 0 for identical images
+pub fn hex_hamming_distance(a: &str, b: &str) -> Result<u32, String> {
+    Ok((hash_from_hex(a)? ^ hash_from_hex(b)?).count_ones())
+}
+
+// Settings that change how an image is turned into a hash
+#[derive(Clone, Copy)]
+pub struct HashOptions {
+    pub trim_borders: bool,
+    pub exif_rotate: bool,
+}
+
+impl HashOptions {
+    // Identifies the settings in the cache, so hashes made with different settings are never mixed
+    fn signature(&self) -> String {
+        format!("trim-borders={};exif-rotate={}", self.trim_borders, self.exif_rotate)
+    }
+}
+
+// Rows or columns whose brightness varies by no more than this count as part of a uniform border
+const BORDER_TOLERANCE: u8 = 12;
+
+// Crop away uniform borders (rows and columns of near-constant colour, working in from each edge) so
+// padding around otherwise identical content doesn't shift the hash. Uniform images are left alone.
+pub fn trim_borders(image: &DynamicImage) -> DynamicImage {
+    let luma = image.to_luma8();
+    let (width, height) = luma.dimensions();
+    let uniform = |pixels: &mut dyn Iterator<Item = u8>| {
+        let (min, max) = pixels.fold((u8::MAX, u8::MIN), |(min, max), p| (min.min(p), max.max(p)));
+        max.saturating_sub(min) <= BORDER_TOLERANCE
+    };
+
+    let (mut top, mut bottom) = (0, height);
+    while top < bottom && uniform(&mut (0..width).map(|x| luma.get_pixel(x, top)[0])) {top += 1;}
+    while bottom > top && uniform(&mut (0..width).map(|x| luma.get_pixel(x, bottom - 1)[0])) {bottom -= 1;}
+
+    let (mut left, mut right) = (0, width);
+    while left < right && uniform(&mut (top..bottom).map(|y| luma.get_pixel(left, y)[0])) {left += 1;}
+    while right > left && uniform(&mut (top..bottom).map(|y| luma.get_pixel(right - 1, y)[0])) {right -= 1;}
+
+    if right - left < 9 || bottom - top < 8 {  // too little left to hash meaningfully
+        return image.clone();
+    }
+    image.crop_imm(left, top, right - left, bottom - top)
+}
+
+// The ways processing a single file can fail, each naming the file so callers can report or match on it
+#[derive(Debug, thiserror::Error)]
+pub enum DedupError {
+    #[error("'{}' is not a directory", path.display())]
+    NotADirectory { path: PathBuf },
+    #[error("'{}' is a directory, not an image", path.display())]
+    IsADirectory { path: PathBuf },
+    #[error("could not decode '{}': {error}", path.display())]
+    DecodeFailed { path: PathBuf, error: image::ImageError },
+    #[error("could not delete '{}': {error}", path.display())]
+    DeleteFailed { path: PathBuf, error: io::Error },
+    #[error("could not copy '{}' to '{}': {error}", path.display(), target.display())]
+    CopyFailed { path: PathBuf, target: PathBuf, error: io::Error },
+    #[error("{count} file(s) could not be deleted")]
+    SomeDeletesFailed { count: u64 },
+    #[error("{count} file(s) could not be copied")]
+    SomeCopiesFailed { count: u64 },
+    #[error("could not move '{}' to '{}': {error}", path.display(), target.display())]
+    MoveFailed { path: PathBuf, target: PathBuf, error: io::Error },
+    #[error("{count} file(s) could not be moved")]
+    SomeMovesFailed { count: u64 },
+    #[error("could not replace '{}' with a hard link to '{}': {error}", path.display(), target.display())]
+    LinkFailed { path: PathBuf, target: PathBuf, error: io::Error },
+    #[error("{count} file(s) could not be replaced with hard links")]
+    SomeLinksFailed { count: u64 },
+}
+
+// The bytes of an image file or archive member
+fn read_image_bytes(path: &str) -> io::Result<Vec<u8>> {
+    match archive_member(path) {
+        Some((archive, member)) => read_archive_member(archive, member),
+        None => fs::read(path),
+    }
+}
+
+// Decode an image file or archive member, from `bytes` if it has been read already
+fn open_image(path: &str, bytes: Option<&[u8]>, context: &RunContext, progress: &dyn Progress) -> image::ImageResult<DynamicImage> {
+    if let Some(bytes) = bytes {
+        // Told apart by the extension, as a file opened by its path is
+        let reader = image::io::Reader::new(io::Cursor::new(bytes));
+        return match image::ImageFormat::from_path(path) {
+            Ok(format) => decode_still(image::io::Reader::with_format(reader.into_inner(), format)),
+            Err(_) => decode_still(reader.with_guessed_format()?),
+        };
+    }
+    context.with_retries("decoding", Path::new(path), progress, || match archive_member(path) {
+        Some((archive, member)) => {
+            decode_still(image::io::Reader::new(io::Cursor::new(read_archive_member(archive, member)?)).with_guessed_format()?)
+        },
+        None => decode_still(image::io::Reader::open(Path::new(path))?),
+    })
+}
+
+// Animation is ignored for matching: an animated PNG or WebP is decoded to its first frame, drawn on the
+// full canvas as a viewer first shows it, so it hashes the same every time and matches a still of that
+// frame. The image crate already decodes a GIF to its first frame and a multi-page TIFF to its first page.
+fn decode_still<R: BufRead + io::Seek>(reader: image::io::Reader<R>) -> image::ImageResult<DynamicImage> {
+    use image::codecs::{png::PngDecoder, webp::WebPDecoder};
+    use image::AnimationDecoder;
+
+    let frames = match reader.format() {
+        Some(image::ImageFormat::Png) => {
+            let decoder = PngDecoder::new(reader.into_inner())?;
+            if !decoder.is_apng() {return DynamicImage::from_decoder(decoder);}
+            decoder.apng().into_frames()
+        },
+        Some(image::ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader.into_inner())?;
+            if !decoder.has_animation() {return DynamicImage::from_decoder(decoder);}
+            decoder.into_frames()
+        },
+        _ => return reader.decode(),
+    };
+    match frames.take(1).next() {
+        Some(frame) => Ok(DynamicImage::ImageRgba8(frame?.into_buffer())),
+        None => Err(image::ImageError::IoError(io::Error::new(io::ErrorKind::InvalidData, "the animation has no frames"))),
+    }
+}
+
+// The EXIF data of an image file or archive member, None if it has none
+fn read_exif(path: &str) -> Option<exif::Exif> {
+    let reader = exif::Reader::new();
+    match archive_member(path) {
+        Some((archive, member)) => {
+            let bytes = read_archive_member(archive, member).ok()?;
+            reader.read_from_container(&mut io::Cursor::new(bytes)).ok()
+        },
+        None => reader.read_from_container(&mut BufReader::new(fs::File::open(path).ok()?)).ok(),
+    }
+}
+
+// The EXIF Orientation tag (1 to 8), None if there is no such tag
+fn exif_orientation(exif: &exif::Exif) -> Option<u32> {
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?.value.get_uint(0)
+}
+
+// EXIF fields that someone has filled in to annotate a photo, rather than ones the camera writes
+const ANNOTATION_TAGS: [exif::Tag; 6] = [
+    exif::Tag::ImageDescription, exif::Tag::UserComment, exif::Tag::Artist,
+    exif::Tag::Copyright, exif::Tag::GPSLatitude, exif::Tag::GPSLongitude,
+];
+
+// A last line of defence before deleting: some member a group keeps must be a different file from every
+// duplicate deleted. Paths are compared canonically, since the same file can be reached through a symlink.
+// Returns what went wrong if the group would be left with no copy at all.
+pub fn loses_every_copy(original: &ImageInfo, also_kept: &[ImageInfo], doomed: &[ImageInfo]) -> Option<String> {
+    let canonical = |path: &str| fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let doomed_files: HashSet<PathBuf> = doomed.iter().map(|info| canonical(&info.path)).collect();
+    let survives = std::iter::once(original).chain(also_kept)
+        .any(|info| !doomed_files.contains(&canonical(&info.path)));
+    (!survives).then(|| format!(
+        "    '{}' is the same file as one of its duplicates: {}",
+        original.path, doomed.iter().map(|info| format!("'{}'", info.path)).collect::<Vec<_>>().join(", ")
+    ))
+}
+
+// Score how much cataloguing work an image carries, for --prefer-metadata: an XMP sidecar next to it
+// ("img.xmp" or "img.jpg.xmp") outweighs any number of annotation fields in its own EXIF data
+pub fn metadata_richness(path: &str) -> u32 {
+    let file = Path::new(path);
+    let sidecar = archive_member(path).is_none() && [file.with_extension("xmp"), PathBuf::from(format!("{}.xmp", path))]
+        .iter()
+        .any(|sidecar| sidecar.is_file());
+    let annotations = read_exif(path)
+        .map(|exif| ANNOTATION_TAGS.iter().filter(|&&tag| exif.get_field(tag, exif::In::PRIMARY).is_some()).count())
+        .unwrap_or(0);
+    100 * sidecar as u32 + annotations as u32
+}
+
+// The (year, month, day) a photo was taken, from its EXIF DateTimeOriginal ("2023:06:01 14:03:22")
+fn exif_date(exif: &exif::Exif) -> Option<(String, String, String)> {
+    let field = exif.get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)?;
+    let exif::Value::Ascii(ref values) = field.value else {return None;};
+    let text = std::str::from_utf8(values.first()?).ok()?;
+    let mut parts = text.get(..10)?.split(':');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    let numeric = [year, month, day].iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()));
+    numeric.then(|| (year.to_string(), month.to_string(), day.to_string()))
+}
+
+// Turn the stored pixels into the image a viewer would display for the given EXIF orientation
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn is_jpeg(path: &str) -> bool {
+    Path::new(path).extension().and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))
+}
+
+// The IJG standard luminance quantization table that JPEG quality settings scale
+const STANDARD_LUMINANCE_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+// Estimate the quality setting (1 to 100) a JPEG was saved with from its luminance quantization table,
+// the way libjpeg scales the standard table. Only the headers are read, nothing is decoded. None if the
+// file has no such table before the image data.
+fn jpeg_quality<R: io::Read>(reader: &mut R) -> Option<u8> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes[..2]).ok()?;
+    if bytes[..2] != [0xff, 0xd8] {return None;}  // no start of image marker
+
+    loop {
+        reader.read_exact(&mut bytes).ok()?;
+        let (marker, len) = (bytes[1], u16::from_be_bytes([bytes[2], bytes[3]]) as usize);
+        if bytes[0] != 0xff || marker == 0xda || len < 2 {return None;}  // start of scan, the tables are over
+        let mut segment = vec![0u8; len - 2];
+        reader.read_exact(&mut segment).ok()?;
+        if marker != 0xdb {continue;}
+
+        // A DQT segment holds one or more tables: a precision/id byte, then 64 values of 8 or 16 bits
+        let mut rest = &segment[..];
+        while let Some((&info, values)) = rest.split_first() {
+            let wide = info >> 4 == 1;
+            let table_len = if wide {128} else {64};
+            if values.len() < table_len {return None;}
+            if info & 0x0f == 0 {
+                let sum: u32 = (0..64)
+                    .map(|i| if wide {u16::from_be_bytes([values[2 * i], values[2 * i + 1]])} else {values[i] as u16} as u32)
+                    .sum();
+                let standard: u32 = STANDARD_LUMINANCE_TABLE.iter().map(|&q| q as u32).sum();
+                let scale = sum as f64 * 100.0 / standard as f64;
+                let quality = if scale <= 100.0 {(200.0 - scale) / 2.0} else {5000.0 / scale};
+                return Some(quality.round().clamp(1.0, 100.0) as u8);
+            }
+            rest = &values[table_len..];
+        }
+    }
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+// The signature and an empty IEND chunk, the least a PNG can be
+const PNG_MIN_LEN: usize = PNG_SIGNATURE.len() + 12;
+
+// Walk a PNG's chunks up to its IEND chunk, false if the data runs out first. Anything after the chunk
+// (such as metadata some tools append) is ignored.
+fn png_is_complete(mut reader: impl BufRead) -> io::Result<bool> {
+    // False where the data runs out
+    fn read(reader: &mut impl BufRead, buf: &mut [u8]) -> io::Result<bool> {
+        match reader.read_exact(buf) {
+            Err(why) if why.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            result => result.map(|_| true),
+        }
+    }
+    let mut signature = [0u8; PNG_SIGNATURE.len()];
+    if !read(&mut reader, &mut signature)? {return Ok(false);}
+    if signature != PNG_SIGNATURE {return Ok(true);}  // not a PNG, left to the decoder
+
+    let mut header = [0u8; 8];  // length and type
+    while read(&mut reader, &mut header)? {
+        if &header[4..] == b"IEND" {return Ok(true);}
+        // The chunk's data and CRC
+        let skip = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64 + 4;
+        if io::copy(&mut io::Read::take(&mut reader, skip), &mut io::sink())? < skip {return Ok(false);}
+    }
+    Ok(false)
+}
+
+// Walk a JPEG's segments and scans up to its end of image marker, false if the data runs out first.
+// Anything after the marker (such as the video of a motion photo) is ignored.
+fn jpeg_is_complete(reader: impl BufRead) -> io::Result<bool> {
+    let mut bytes = reader.bytes();
+    let mut next = || bytes.next().transpose();
+    if (next()?, next()?) != (Some(0xff), Some(0xd8)) {return Ok(true);}  // not a JPEG, left to the decoder
+
+    let mut pending = None;  // a marker found at the end of a scan
+    loop {
+        let marker = match pending.take() {
+            Some(marker) => marker,
+            None => {
+                if next()? != Some(0xff) {return Ok(false);}
+                let mut marker = 0xff;
+                while marker == 0xff {
+                    let Some(byte) = next()? else {return Ok(false);};
+                    marker = byte;
+                }
+                marker
+            },
+        };
+        match marker {
+            0xd9 => return Ok(true),
+            0x01 | 0xd0..=0xd7 => continue,  // markers without a length
+            _ => {},
+        }
+        let (Some(high), Some(low)) = (next()?, next()?) else {return Ok(false);};
+        for _ in 2..u16::from_be_bytes([high, low]) {
+            if next()?.is_none() {return Ok(false);}
+        }
+        if marker != 0xda {continue;}
+
+        // Entropy coded data follows a start of scan, up to the first marker that isn't a stuffed 0xff
+        // byte or a restart marker
+        let mut after_ff = false;
+        loop {
+            let Some(byte) = next()? else {return Ok(false);};
+            match (after_ff, byte) {
+                (_, 0xff) => after_ff = true,
+                (true, 0x00 | 0xd0..=0xd7) => after_ff = false,
+                (true, marker) => {
+                    pending = Some(marker);
+                    break;
+                },
+                (false, _) => {},
+            }
+        }
+    }
+}
+
+// Why an image looks cut off, as interrupted downloads and copies leave them. Such files can still
+// decode, with the missing part filled in, into a hash of mostly garbage. Only plain JPEG and PNG
+// files are checked; None means no sign of damage.
+// `bytes` are the file's, if it has been read already.
+fn truncation(path: &str, bytes: Option<&[u8]>) -> Option<&'static str> {
+    if archive_member(path).is_some() {return None;}
+    let is_png = Path::new(path).extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let too_short = "truncated, it is too short to be an image";
+
+    if is_png {
+        let complete = match bytes {
+            Some(bytes) if bytes.len() < PNG_MIN_LEN => return Some(too_short),
+            Some(bytes) => png_is_complete(bytes),
+            None => {
+                let file = fs::File::open(path).ok()?;
+                if file.metadata().ok()?.len() < PNG_MIN_LEN as u64 {return Some(too_short);}
+                png_is_complete(BufReader::new(file))
+            },
+        };
+        (!complete.ok()?).then_some("truncated, it has no PNG end chunk")
+    } else if is_jpeg(path) {
+        let complete = match bytes {
+            Some(bytes) => jpeg_is_complete(bytes),
+            None => jpeg_is_complete(BufReader::new(fs::File::open(path).ok()?)),
+        };
+        (!complete.ok()?).then_some("truncated, its data ends before the JPEG end of image marker")
+    } else {
+        None
+    }
+}
+
+// The estimated quality of a JPEG file or archive member, None for other formats
+pub fn estimate_jpeg_quality(path: &str) -> Option<u8> {
+    if !is_jpeg(path) {return None;}
+    match archive_member(path) {
+        Some((archive, member)) => jpeg_quality(&mut io::Cursor::new(read_archive_member(archive, member).ok()?)),
+        None => jpeg_quality(&mut BufReader::new(fs::File::open(path).ok()?)),
+    }
+}
+
+// The formats that can carry an EXIF Orientation tag, which their decoders leave for the caller to apply
+fn may_carry_exif(path: &str) -> bool {
+    Path::new(path).extension().and_then(|ext| ext.to_str()).is_some_and(|ext| {
+        ["jpg", "jpeg", "tif", "tiff", "png", "webp"].contains(&ext.to_ascii_lowercase().as_str())
+    })
+}
+
+// Decode an image as it is displayed: phone cameras often store photos sideways with an EXIF tag saying
+// how to turn them, and two copies of a photo only hash alike once both are turned the right way up
+pub fn open_oriented(path: &str, options: HashOptions, context: &RunContext, progress: &dyn Progress) -> Result<DynamicImage, DedupError> {
+    decode_oriented(path, None, options, context, progress)
+}
+
+// Like open_oriented, from `bytes` if the file has been read already
+fn decode_oriented(
+    path: &str, bytes: Option<&[u8]>, options: HashOptions, context: &RunContext, progress: &dyn Progress
+) -> Result<DynamicImage, DedupError> {
+    let image = open_image(path, bytes, context, progress).map_err(|error| DedupError::DecodeFailed { path: PathBuf::from(path), error })?;
+    if !options.exif_rotate || !may_carry_exif(path) {return Ok(image);}
+
+    let exif = match bytes {
+        Some(bytes) => exif::Reader::new().read_from_container(&mut io::Cursor::new(bytes)).ok(),
+        None => read_exif(path),
+    };
+    let orientation = exif.and_then(|exif| exif_orientation(&exif));
+    Ok(apply_orientation(image, orientation.unwrap_or(1)))
+}
+
+// Hashes by image path
+type HashesByPath = Mutex<HashMap<String, u64>>;
+
+// Share of the width and height cut from each side for the centre hash, where reposts tend to put
+// logos, watermarks and captions
+const CENTRE_MARGIN: f64 = 0.15;
+
+fn centre_hash(image: &DynamicImage) -> u64 {
+    let (width, height) = (image.width(), image.height());
+    let (x, y) = ((width as f64 * CENTRE_MARGIN) as u32, (height as f64 * CENTRE_MARGIN) as u32);
+    Dhash::new(&image.crop_imm(x, y, width - 2 * x, height - 2 * y)).to_u64()
+}
+
+// The average hash: one bit per pixel of an 8x8 grey thumbnail, set where it is brighter than the mean.
+// It looks at brightness rather than gradients, so it rarely collides on the same images as the dHash.
+fn average_hash(image: &DynamicImage) -> u64 {
+    let thumbnail = image.resize_exact(8, 8, image::imageops::FilterType::Triangle).to_luma8();
+    let total: u32 = thumbnail.pixels().map(|pixel| pixel.0[0] as u32).sum();
+    thumbnail.pixels().enumerate()
+        .filter(|(_, pixel)| pixel.0[0] as u32 * 64 > total)
+        .fold(0, |hash, (i, _)| hash | 1 << (63 - i))
+}
+
+// A dHash of side × side bits, in as many 64-bit words as that takes. The image is shrunk to a grey
+// (side + 1) × side thumbnail and each bit is set where a pixel is brighter than the one to its right,
+// like the 64-bit dHash of an 8 × 8 grid, so more of the picture's structure is kept.
+#[derive(Clone, PartialEq, Eq)]
+pub struct WideHash(Vec<u64>);
+
+impl WideHash {
+    pub fn new(image: &DynamicImage, side: u32) -> WideHash {
+        let thumbnail = image.resize_exact(side + 1, side, image::imageops::FilterType::Triangle).to_luma8();
+        let mut words = vec![0u64; (side * side).div_ceil(64) as usize];
+        for y in 0..side {
+            for x in 0..side {
+                if thumbnail.get_pixel(x, y).0[0] > thumbnail.get_pixel(x + 1, y).0[0] {
+                    let bit = (y * side + x) as usize;
+                    words[bit / 64] |= 1 << (bit % 64);
+                }
+            }
+        }
+        WideHash(words)
+    }
+
+    pub fn distance(&self, other: &WideHash) -> u32 {
+        self.0.iter().zip(&other.0).map(|(a, b)| (a ^ b).count_ones()).sum()
+    }
+}
+
+impl RunContext {
+    // Whether any hash is wanted besides the dHash, which cached and resumed images have to be decoded again for
+    pub fn needs_extra_hashes(&self) -> bool {
+        self.extra_hashes().next().is_some() || self.wide_hashes.is_some()
+    }
+
+    // The hashes the options ask for on top of the dHash, with the functions that make them
+    pub fn extra_hashes(&self) -> impl Iterator<Item = (&HashesByPath, fn(&DynamicImage) -> u64)> {
+        [(&self.centre_hashes, centre_hash as fn(&DynamicImage) -> u64), (&self.average_hashes, average_hash)].into_iter()
+            .filter_map(|(hashes, hasher)| Some((hashes.as_ref()?, hasher)))
+    }
+
+    // Make every extra hash of an image from the same decode as its dHash
+    pub fn record_extra_hashes(&self, path: &str, image: &DynamicImage) {
+        for (hashes, hasher) in self.extra_hashes() {
+            let hash = hasher(image);
+            hashes.lock().unwrap().insert(path.to_string(), hash);
+        }
+        if let Some((side, hashes)) = &self.wide_hashes {
+            let hash = WideHash::new(image, *side);
+            hashes.lock().unwrap().insert(path.to_string(), hash);
+        }
+    }
+
+    // An exact copy has the same extra hashes as the file decoded in its place
+    pub fn copy_extra_hashes(&self, first: &str, path: &str) {
+        for (hashes, _) in self.extra_hashes() {
+            let mut hashes = hashes.lock().unwrap();
+            if let Some(&hash) = hashes.get(first) {hashes.insert(path.to_string(), hash);}
+        }
+        if let Some((_, hashes)) = &self.wide_hashes {
+            let mut hashes = hashes.lock().unwrap();
+            if let Some(hash) = hashes.get(first).cloned() {hashes.insert(path.to_string(), hash);}
+        }
+    }
+
+    // The first image seen with the same hash, if streaming is on and there was one. These early matches are
+    // only provisional: the final groups still apply every rule and are decided once all hashes are in.
+    pub fn stream_match(&self, path: &str, hash: u64) -> Option<String> {
+        let mut seen = self.match_stream.as_ref()?.lock().unwrap();
+        match seen.get(&hash) {
+            Some(first) => Some(first.clone()),
+            None => {
+                seen.insert(hash, path.to_string());
+                None
+            },
+        }
+    }
+}
+
+// Decode and hash a single image, keeping the decoded dimensions so they never have to be read again.
+// The dimensions are always those of the full (displayed) image, even when borders are trimmed for hashing.
+pub fn hash_image(path: &str, options: HashOptions, context: &RunContext, progress: &dyn Progress) -> Result<(Dhash, Dimensions), DedupError> {
+    hash_image_from(path, None, options, context, progress)
+}
+
+// Like hash_image, from `bytes` if the file has been read already
+fn hash_image_from(
+    path: &str, bytes: Option<&[u8]>, options: HashOptions, context: &RunContext, progress: &dyn Progress
+) -> Result<(Dhash, Dimensions), DedupError> {
+    let im_file = decode_oriented(path, bytes, options, context, progress)?;
+    let dimensions = (im_file.width(), im_file.height());
+    let im_file = if options.trim_borders {trim_borders(&im_file)} else {im_file};
+    let hash = Dhash::new(&im_file);
+    context.record_extra_hashes(path, &im_file);
+    Ok((hash, dimensions))
+}
+
+// Side of the square thumbnails compared by --pixel-threshold
+const THUMBNAIL_SIZE: u32 = 32;
+
+// A small RGB copy of an image for comparing content directly, trimmed the same way as for hashing
+pub fn thumbnail(path: &str, options: HashOptions, context: &RunContext, progress: &dyn Progress) -> Option<image::RgbImage> {
+    let image = open_oriented(path, options, context, progress).ok()?;
+    let image = if options.trim_borders {trim_borders(&image)} else {image};
+    Some(image.resize_exact(THUMBNAIL_SIZE, THUMBNAIL_SIZE, image::imageops::FilterType::Triangle).to_rgb8())
+}
+
+// Mean absolute difference per channel between two thumbnails, from 0 (identical) to 255
+pub fn mean_pixel_difference(a: &image::RgbImage, b: &image::RgbImage) -> f64 {
+    let total: u64 = a.as_raw().iter().zip(b.as_raw()).map(|(x, y)| x.abs_diff(*y) as u64).sum();
+    total as f64 / a.as_raw().len() as f64
+}
+
+// Hash (path, file size) pairs as hashing thread `worker` of `workers`
+pub fn generate_hashes(
+    images: Vec<(String, u64)>, worker: usize, workers: usize, context: &RunContext, progress: &dyn Progress, options: HashOptions
+) -> io::Result<Vec<(String, Dhash, Dimensions)>> {
+    let mut hashes: Vec<(String, Dhash, Dimensions)> = vec![];
+    progress.on_hash_start(worker, workers, images.iter().map(|(_, size)| size).sum());
+
+    for (im, size) in images {
+        if context.aborted() {break;}
+        if context.out_of_time() {
+            context.defer();
+            continue;
+        }
+        hashes.extend(hash_one(im, size, None, worker, context, progress, options));
+    }
+
+    progress.on_hash_done(worker);
+
+    Ok(hashes)
+}
+
+// Hash one image for hashing worker `worker`, None if it is damaged or can't be decoded. `read` is what
+// came of reading the file ahead of time, if a reader thread did.
+fn hash_one(
+    im: String, size: u64, read: Option<io::Result<Vec<u8>>>, worker: usize, context: &RunContext, progress: &dyn Progress,
+    options: HashOptions,
+) -> Option<HashedImage> {
+    let bytes = match read.transpose() {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            progress.on_hash_progress(worker, &im, size);
+            context.progress_advance(1);
+            let why = DedupError::DecodeFailed { path: PathBuf::from(&im), error: image::ImageError::IoError(error) };
+            return hash_failed(im, why, context, progress);
+        },
+    };
+    if let Some(why) = truncation(&im, bytes.as_deref()) {
+        context.record_skip(SkipReason::Truncated, Path::new(&im), why, progress);
+        context.damaged.lock().unwrap().push(im.clone());
+        progress.on_hash_progress(worker, &im, size);
+        context.progress_advance(1);
+        return None;
+    }
+    let result = hash_image_from(&im, bytes.as_deref(), options, context, progress);
+    progress.on_hash_progress(worker, &im, size);
+    context.progress_advance(1);
+    match result {
+        Ok((hash, dimensions)) => {
+            if let Some(first) = context.stream_match(&im, hash.to_u64()) {
+                progress.on_message(&format!("Likely duplicate: '{}' matches '{}'", im, first));
+            }
+            if let Some(checkpoint) = &context.checkpoint {
+                checkpoint.record(&im, hash, dimensions);
+            }
+            Some((im, hash, dimensions))
+        },
+        Err(why) => hash_failed(im, why, context, progress),
+    }
+}
+
+// Note an image that couldn't be decoded, always None
+fn hash_failed(im: String, why: DedupError, context: &RunContext, progress: &dyn Progress) -> Option<HashedImage> {
+    context.file_failed(&why);
+    context.record_skip(SkipReason::DecodeFailed, Path::new(&im), why, progress);
+    context.damaged.lock().unwrap().push(im);
+    None
+}
+
+// Below this many images, spreading the work over several threads costs more than it saves
+const MULTITHREAD_MIN_IMAGES: usize = 32;
+
+// How many threads are worth using to hash `image_count` images
+pub fn hashing_threads(image_count: usize, thread_count: usize) -> usize {
+    // Never more threads than images, or a thread would have nothing to do, and never none at all
+    if image_count < MULTITHREAD_MIN_IMAGES {1} else {thread_count.clamp(1, image_count)}
+}
+
+// Runs with fewer images than this finish before an estimate would be of any use
+pub const ESTIMATE_MIN_IMAGES: usize = 200;
+
+// How many images are timed for the estimate
+const ESTIMATE_SAMPLE: usize = 5;
+
+// Hash a handful of images spread over `paths` and extrapolate how long the rest will take by their
+// size, given `parallel` images are decoded at a time. The sampled images are taken out of `paths`
+// and their hashes returned for reuse; `progress` counts them like any other hashed image.
+pub fn estimate_hashing(
+    paths: &mut Vec<(String, u64)>, parallel: usize, context: &RunContext, progress: &dyn Progress, options: HashOptions
+) -> io::Result<(Vec<HashedImage>, Duration)> {
+    // With fewer than ESTIMATE_SAMPLE paths, every one is sampled
+    let step = (paths.len() / ESTIMATE_SAMPLE).max(1);
+    let (sample, rest): (Vec<_>, Vec<_>) = std::mem::take(paths).into_iter().enumerate().partition(|(i, _)| i % step == 0);
+    let sample: Vec<(String, u64)> = sample.into_iter().map(|(_, path)| path).collect();
+    *paths = rest.into_iter().map(|(_, path)| path).collect();
+
+    let sample_bytes: u64 = sample.iter().map(|(_, size)| size).sum();
+    let rest_bytes: u64 = paths.iter().map(|(_, size)| size).sum();
+    let start = Instant::now();
+    let hashes = with_decoder_pool(context.decoder_threads, || generate_hashes(sample, 0, 1, context, progress, options))?;
+    let secs_per_byte = start.elapsed().as_secs_f64() / sample_bytes.max(1) as f64;
+
+    Ok((hashes, Duration::from_secs_f64(secs_per_byte * rest_bytes as f64 / parallel as f64)))
+}
+
+// Hash the images on `thread_count` threads that take them one at a time as they come free, so a thread
+// that drew a run of big files never leaves the others idle. Hashes come back in the order of `paths`.
+pub fn generate_hashes_multithreaded(
+    paths: Vec<(String, u64)>, thread_count: usize, context: &RunContext, progress: &dyn Progress, options: HashOptions
+) -> io::Result<Vec<(String, Dhash, Dimensions)>> {
+    hash_on_pool(paths, thread_count, 0, 1, context, progress, options)
+}
+
+// Files each hashing thread may have waiting in memory for it under --read-threads
+const READ_AHEAD: usize = 2;
+
+// Hash the images in two stages, for storage where reading is the slow part: `read_threads` threads read
+// the files into memory, in the order of `paths`, and pass them through a queue to `hash_threads` threads
+// that decode and hash them. The queue holds at most READ_AHEAD files per hashing thread, so a fast reader
+// waits rather than filling memory. Hashes come back in the order of `paths`.
+pub fn generate_hashes_pipelined(
+    paths: Vec<(String, u64)>, read_threads: usize, hash_threads: usize, context: &RunContext, progress: &dyn Progress,
+    options: HashOptions,
+) -> io::Result<Vec<(String, Dhash, Dimensions)>> {
+    let decoders = rayon::ThreadPoolBuilder::new().num_threads(hash_threads.max(1)).build().map_err(io::Error::other)?;
+    progress.on_hash_start(0, 1, paths.iter().map(|(_, size)| size).sum());
+
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = std::sync::mpsc::sync_channel(hash_threads.max(1) * READ_AHEAD);
+    let receiver = Mutex::new(receiver);
+    let (paths, next) = (&paths, &next);
+    let mut hashes: Vec<(usize, HashedImage)> = thread::scope(|scope| {
+        for _ in 0..read_threads.max(1) {
+            let sender = sender.clone();
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((im, size)) = paths.get(i) else {break};
+                if context.aborted() {break;}
+                if context.out_of_time() {
+                    context.defer();
+                    continue;
+                }
+                let read = context.with_retries("reading", Path::new(im), progress, || read_image_bytes(im));
+                // Only fails once every hashing thread is gone
+                if sender.send((i, im.clone(), *size, read)).is_err() {break;}
+            });
+        }
+        drop(sender);
+        // Each hashing thread is one of the pool's, so its decoder pool is made the same way as without readers
+        decoders.broadcast(|_| {
+            let mut hashes = vec![];
+            loop {
+                let Ok((i, im, size, read)) = receiver.lock().unwrap().recv() else {break};
+                // What was read already is dropped, but the queue is drained so no reader is left waiting on it
+                if context.aborted() {continue;}
+                let hash = with_decoder_pool(context.decoder_threads, || hash_one(im, size, Some(read), 0, context, progress, options));
+                hashes.extend(hash.map(|hash| (i, hash)));
+            }
+            hashes
+        }).into_iter().flatten().collect()
+    });
+    hashes.sort_unstable_by_key(|&(i, _)| i);
+
+    progress.on_hash_done(0);
+
+    Ok(hashes.into_iter().map(|(_, hash)| hash).collect())
+}
+
+// Hash `paths` on a rayon pool of its own as hashing worker `worker` of `workers`, behind one progress bar
+fn hash_on_pool(
+    paths: Vec<(String, u64)>, thread_count: usize, worker: usize, workers: usize, context: &RunContext, progress: &dyn Progress,
+    options: HashOptions,
+) -> io::Result<Vec<HashedImage>> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(thread_count.max(1)).build().map_err(io::Error::other)?;
+    progress.on_hash_start(worker, workers, paths.iter().map(|(_, size)| size).sum());
+
+    let hashes = pool.install(|| paths.into_par_iter()
+        .with_max_len(1)
+        .filter_map(|(im, size)| {
+            if context.aborted() {return None;}
+            if context.out_of_time() {
+                context.defer();
+                return None;
+            }
+            with_decoder_pool(context.decoder_threads, || hash_one(im, size, None, worker, context, progress, options))
+        })
+        .collect());
+
+    progress.on_hash_done(worker);
+
+    Ok(hashes)
+}
+
+// Split the images between threads root by root for --per-root-threads, so each scanned directory (often
+// its own drive) is read by its own threads and a slow drive never holds up the others' threads. Every
+// root with images gets a thread, and the rest go one at a time to whichever has the most images per thread.
+pub fn split_per_root(paths: Vec<(String, u64)>, roots: &[PathBuf], thread_count: usize) -> Vec<(Vec<(String, u64)>, usize)> {
+    let mut by_root: Vec<Vec<(String, u64)>> = vec![vec![]; roots.len()];
+    for (path, size) in paths {
+        let root = root_index(Path::new(&path), roots).unwrap_or(0);
+        by_root[root].push((path, size));
+    }
+    by_root.retain(|paths| !paths.is_empty());
+
+    let mut threads = vec![1; by_root.len()];
+    for _ in by_root.len()..thread_count {
+        // A root never gets more threads than it has images, and with no images there is nobody to give one
+        let Some(busiest) = (0..by_root.len())
+            .filter(|&i| threads[i] < by_root[i].len())
+            .max_by_key(|&i| by_root[i].len() / threads[i]) else {break};
+        threads[busiest] += 1;
+    }
+    by_root.into_iter().zip(threads).collect()
+}
+
+thread_local! {
+    // Made the first time a hashing thread decodes, and kept for every image it decodes after that with as
+    // many decoder threads
+    static DECODER_POOL: std::cell::RefCell<Option<(usize, Option<Arc<rayon::ThreadPool>>)>> = const { std::cell::RefCell::new(None) };
+}
+
+// Run hashing work in the current thread's own rayon pool of `threads` threads, which the JPEG decoder spreads
+// the work on each image over. Otherwise every decoder shares the pool of the hashing threads, or rayon's global pool.
+pub fn with_decoder_pool<T: Send>(threads: usize, work: impl FnOnce() -> T + Send) -> T {
+    let pool = DECODER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.as_ref().is_none_or(|(made_for, _)| *made_for != threads) {
+            *pool = Some((threads, rayon::ThreadPoolBuilder::new().num_threads(threads).build().ok().map(Arc::new)));
+        }
+        pool.as_ref().and_then(|(_, pool)| pool.clone())
+    });
+    match pool {
+        Some(pool) => pool.install(work),
+        None => work(),
+    }
+}
+
+// Hash each directory's images on threads of its own for --per-root-threads, each part with its own
+// progress bar, all at the same time. Hashes come back root by root.
+pub fn hash_per_root(
+    parts: Vec<(Vec<(String, u64)>, usize)>, context: &RunContext, progress: &dyn Progress, options: HashOptions
+) -> io::Result<Vec<(String, Dhash, Dimensions)>> {
+    let workers = parts.len();
+    let hashed: Vec<io::Result<Vec<HashedImage>>> = thread::scope(|scope| {
+        let handles: Vec<_> = parts.into_iter().enumerate()
+            .map(|(i, (paths, threads))| scope.spawn(move || hash_on_pool(paths, threads, i, workers, context, progress, options)))
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    let mut hashes = vec![];
+    for part in hashed {
+        hashes.extend(part?);
+    }
+    Ok(hashes)
+}
+
+// A file with the same bytes as another, and the path of that other file
+type ExactCopy = (String, String);
+
+// 64 bit FNV-1a hash of a file's bytes, read in chunks so large files aren't loaded whole
+fn content_hash(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::with_capacity(1 << 16, fs::File::open(path)?);
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {return Ok(hash);}
+        for &byte in chunk {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        let len = chunk.len();
+        reader.consume(len);
+    }
+}
+
+// Whether two files have exactly the same bytes, compared in full rather than by a hash of them
+pub fn files_identical(a: &Path, b: &Path) -> io::Result<bool> {
+    if fs::metadata(a)?.len() != fs::metadata(b)?.len() {return Ok(false);}
+    let mut a = BufReader::with_capacity(1 << 16, fs::File::open(a)?);
+    let mut b = BufReader::with_capacity(1 << 16, fs::File::open(b)?);
+    loop {
+        let (chunk_a, chunk_b) = (a.fill_buf()?, b.fill_buf()?);
+        if chunk_a.is_empty() || chunk_b.is_empty() {return Ok(chunk_a.is_empty() && chunk_b.is_empty());}
+        let len = chunk_a.len().min(chunk_b.len());
+        if chunk_a[..len] != chunk_b[..len] {return Ok(false);}
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+// Files with identical bytes always hash alike, so only one file of each set of exact copies needs to be
+// decoded. Only files that share their size with another are read. Returns the files that still need
+// decoding, and every exact copy paired with the file that is decoded in its place.
+pub fn split_exact_copies(paths: Vec<(String, u64)>) -> (Vec<(String, u64)>, Vec<ExactCopy>) {
+    let mut size_counts: HashMap<u64, usize> = HashMap::new();
+    for (path, size) in &paths {
+        if archive_member(path).is_none() {*size_counts.entry(*size).or_default() += 1;}
+    }
+    let content: Vec<Option<u64>> = paths.par_iter()
+        .map(|(path, size)| {
+            let shared = archive_member(path).is_none() && size_counts[size] > 1;
+            if shared {content_hash(Path::new(path)).ok()} else {None}
+        })
+        .collect();
+
+    let mut firsts: HashMap<(u64, u64), String> = HashMap::new();
+    let mut to_decode = vec![];
+    let mut copies = vec![];
+    for ((path, size), content) in paths.into_iter().zip(content) {
+        match content.map(|content| firsts.entry((size, content))) {
+            Some(std::collections::hash_map::Entry::Occupied(first)) => copies.push((path, first.get().clone())),
+            Some(std::collections::hash_map::Entry::Vacant(slot)) => {
+                slot.insert(path.clone());
+                to_decode.push((path, size));
+            },
+            None => to_decode.push((path, size)),
+        }
+    }
+
+    (to_decode, copies)
+}
+
+// A hash computed on a previous run, trusted only while the file's size and mtime are unchanged
+pub struct CacheEntry {
+    pub size: u64,
+    modified: u128,
+    pub hash: Dhash,
+    pub dimensions: Dimensions,
+}
+
+// Persistent store of hashes keyed by absolute path, saved as one tab separated line per file.
+// `options` is the signature of the HashOptions the hashes were made with.
+pub struct HashCache {
+    pub entries: HashMap<String, CacheEntry>,
+    pub options: String,
+}
+
+impl HashCache {
+    const HEADER: &'static str = "# fast-dedup hash cache v1";
+    const OPTIONS: &'static str = "# options: ";
+
+    pub fn new(options: &HashOptions) -> HashCache {
+        HashCache { entries: HashMap::new(), options: options.signature() }
+    }
+
+    // Take an advisory lock on "<cache>.lock" for as long as the returned file is open, so two runs can't
+    // interleave their saves. The lock is separate from the cache because saving recreates the cache file.
+    // The OS drops the lock when the process exits, however that happens, so it can never go stale.
+    pub fn lock(file: &Path) -> io::Result<fs::File> {
+        let mut lock_path = file.as_os_str().to_owned();
+        lock_path.push(".lock");
+        let lock = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+        match lock.try_lock_exclusive() {
+            Ok(()) => Ok(lock),
+            Err(why) if why.kind() == fs2::lock_contended_error().kind() => Err(io::Error::other(
+                "it is in use by another run of fast-dedup, wait for that to finish or use a different --cache"
+            )),
+            Err(why) => Err(why),
+        }
+    }
+
+    // Load a cache file, treating a missing file, or one made with different hash options, as an empty cache
+    pub fn load(file: &Path, options: &HashOptions, progress: &dyn Progress) -> io::Result<HashCache> {
+        let cache = match HashCache::read(file) {
+            Ok(cache) => cache,
+            Err(why) if why.kind() == io::ErrorKind::NotFound => return Ok(HashCache::new(options)),
+            Err(why) => return Err(why),
+        };
+        // Caches from before the options line was added were all made with the default options
+        if !cache.options.is_empty() && cache.options != options.signature() {
+            progress.on_warning(&format!("cache '{}' was made with different hash options, ignoring it", file.display()));
+            return Ok(HashCache::new(options));
+        }
+        Ok(HashCache { options: options.signature(), ..cache })
+    }
+
+    // Read every entry of a cache file, along with the options it was made with (empty if it doesn't say)
+    pub fn read(file: &Path) -> io::Result<HashCache> {
+        let mut cache = HashCache { entries: HashMap::new(), options: String::new() };
+        let reader = BufReader::new(fs::File::open(file)?);
+
+        for line in reader.lines() {
+            let line = line?;
+            if let Some(stored) = line.strip_prefix(HashCache::OPTIONS) {
+                cache.options = stored.to_string();
+            }
+            if line.starts_with('#') || line.is_empty() {continue;}
+
+            // hash, width, height, size, mtime, path (last, so it may contain tabs)
+            let fields: Vec<&str> = line.splitn(6, '\t').collect();
+            if fields.len() != 6 {continue;}
+            let parsed = (
+                hash_from_hex(fields[0]).map(Dhash::from_u64),
+                fields[1].parse::<u32>(),
+                fields[2].parse::<u32>(),
+                fields[3].parse::<u64>(),
+                fields[4].parse::<u128>(),
+            );
+            if let (Ok(hash), Ok(width), Ok(height), Ok(size), Ok(modified)) = parsed {
+                cache.entries.insert(
+                    fields[5].to_string(),
+                    CacheEntry { size, modified, hash, dimensions: (width, height) },
+                );
+            }
+        }
+
+        Ok(cache)
+    }
+
+    pub fn save(&self, file: &Path) -> io::Result<()> {
+        let mut writer = BufWriter::new(fs::File::create(file)?);
+        writeln!(writer, "{}", HashCache::HEADER)?;
+        writeln!(writer, "{}{}", HashCache::OPTIONS, self.options)?;
+
+        for (path, entry) in &self.entries {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                hash_to_hex(entry.hash.to_u64()), entry.dimensions.0, entry.dimensions.1, entry.size, entry.modified, path
+            )?;
+        }
+
+        writer.flush()
+    }
+
+    // The cache key and current (size, mtime) of a file, or None if it can't be cached
+    fn stat(path: &str) -> Option<(String, u64, u128)> {
+        let key = std::path::absolute(path).ok()?.to_str()?.to_string();
+        if key.contains('\n') {return None;}
+        let meta = fs::metadata(path).ok()?;
+        let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+        Some((key, meta.len(), modified))
+    }
+
+    // Look up a file, ignoring entries made stale by a change in size or mtime
+    pub fn get(&self, path: &str) -> Option<(Dhash, Dimensions)> {
+        let (key, size, modified) = HashCache::stat(path)?;
+        let entry = self.entries.get(&key)?;
+        if entry.size != size || entry.modified != modified {return None;}
+        Some((entry.hash, entry.dimensions))
+    }
+
+    pub fn insert(&mut self, path: &str, hash: Dhash, dimensions: Dimensions) {
+        if let Some((key, size, modified)) = HashCache::stat(path) {
+            self.entries.insert(key, CacheEntry { size, modified, hash, dimensions });
+        }
+    }
+
+    // Drop entries whose file was deleted, or changed since it was hashed, so the cache doesn't keep
+    // growing with files that are gone. Returns how many were dropped
+    pub fn prune(&mut self) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|key, entry| match HashCache::stat(key) {
+            Some((_, size, modified)) => entry.size == size && entry.modified == modified,
+            None => false,
+        });
+        before - self.entries.len()
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        if let Some((key, _, _)) = HashCache::stat(path) {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+// Buffered hashes are flushed to the checkpoint at least this often, so an interruption loses little
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+// Scan state of one run for --resume: the files the scan found, written once up front, then a line
+// for each hash as it is made. Unlike the cache it only lives until the run finishes successfully.
+pub struct Checkpoint {
+    file: PathBuf,
+    writer: Mutex<(BufWriter<fs::File>, Instant)>,  // and when it was last flushed
+    keep: AtomicBool,  // hashing was cut short by the time budget, so the checkpoint is still needed
+}
+
+// What a checkpoint holds: the files found and the hashes made of them so far
+pub struct Resumed {
+    pub files: Vec<(String, u64)>,
+    pub hashes: HashMap<String, (u64, Dhash, Dimensions)>,  // by path, with the size the file had then
+}
+
+impl Checkpoint {
+    const HEADER: &'static str = "# fast-dedup checkpoint v1";
+
+    pub fn create(file: &Path, options: &HashOptions, files: &[(String, u64)]) -> io::Result<Checkpoint> {
+        let mut writer = BufWriter::new(fs::File::create(file)?);
+        writeln!(writer, "{}", Checkpoint::HEADER)?;
+        writeln!(writer, "{}{}", HashCache::OPTIONS, options.signature())?;
+        for (path, size) in files {
+            writeln!(writer, "file\t{}\t{}", size, path)?;
+        }
+        writer.flush()?;
+        Ok(Checkpoint { file: file.to_path_buf(), writer: Mutex::new((writer, Instant::now())), keep: AtomicBool::new(false) })
+    }
+
+    // Carry on writing the checkpoint of an interrupted run, after the hashes it already holds
+    pub fn append(file: &Path) -> io::Result<Checkpoint> {
+        let writer = BufWriter::new(fs::OpenOptions::new().append(true).open(file)?);
+        Ok(Checkpoint { file: file.to_path_buf(), writer: Mutex::new((writer, Instant::now())), keep: AtomicBool::new(false) })
+    }
+
+    // Read a checkpoint, which only makes sense with the hash options it was made with
+    pub fn read(file: &Path, options: &HashOptions) -> io::Result<Resumed> {
+        let mut lines = BufReader::new(fs::File::open(file)?).lines();
+        if lines.next().transpose()?.as_deref() != Some(Checkpoint::HEADER) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "it is not a fast-dedup checkpoint"));
+        }
+        let mut resumed = Resumed { files: vec![], hashes: HashMap::new() };
+        let mut sizes: HashMap<String, u64> = HashMap::new();
+        for line in lines {
+            let line = line?;
+            if let Some(stored) = line.strip_prefix(HashCache::OPTIONS) {
+                if stored != options.signature() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                        "it was made with different hash options ({}), run with the same options or delete it", stored
+                    )));
+                }
+            }
+            // file, size, path; or hash, hash, width, height, path. Paths are last so they may contain tabs,
+            // which is why each kind of line is split into only as many fields as it has.
+            let Some((kind, rest)) = line.split_once('\t') else {continue};
+            let fields: Vec<&str> = match kind {
+                "file" => rest.splitn(2, '\t').collect(),
+                "hash" => rest.splitn(4, '\t').collect(),
+                _ => continue,
+            };
+            match (kind, &fields[..]) {
+                ("file", &[size, path]) => if let Ok(size) = size.parse::<u64>() {
+                    sizes.insert(path.to_string(), size);
+                    resumed.files.push((path.to_string(), size));
+                },
+                ("hash", &[hash, width, height, path]) => {
+                    let parsed = (hash_from_hex(hash), width.parse::<u32>(), height.parse::<u32>(), sizes.get(path));
+                    if let (Ok(hash), Ok(width), Ok(height), Some(&size)) = parsed {
+                        resumed.hashes.insert(path.to_string(), (size, Dhash::from_u64(hash), (width, height)));
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(resumed)
+    }
+
+    // Record a hash, flushing what has built up once CHECKPOINT_INTERVAL has passed. A checkpoint that
+    // can't be written shouldn't stop the run, so write errors are ignored.
+    fn record(&self, path: &str, hash: Dhash, dimensions: Dimensions) {
+        if path.contains('\n') {return;}
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writeln!(writer.0, "hash\t{}\t{}\t{}\t{}", hash_to_hex(hash.to_u64()), dimensions.0, dimensions.1, path);
+        if writer.1.elapsed() >= CHECKPOINT_INTERVAL {
+            let _ = writer.0.flush();
+            writer.1 = Instant::now();
+        }
+    }
+
+    pub fn finish_hashing(&self, incomplete: bool) {
+        let _ = self.writer.lock().unwrap().0.flush();
+        self.keep.store(incomplete, Ordering::Relaxed);
+    }
+
+    pub fn remove(&self, progress: &dyn Progress) {
+        if self.keep.load(Ordering::Relaxed) {
+            progress.on_message(&format!("Kept checkpoint '{}', --resume it to hash the images that were left", self.file.display()));
+        } else if let Err(why) = fs::remove_file(&self.file) {
+            progress.on_warning(&format!("could not remove checkpoint '{}': {}", self.file.display(), why));
+        }
+    }
+}
+
+impl Resumed {
+    // Drop the files that have gone since the checkpoint was written, and the hashes of files that have
+    // changed size. Archive members stand and fall with their archive.
+    pub fn still_present(self, progress: &dyn Progress) -> Resumed {
+        let current_size = |path: &str| {
+            let file = archive_member(path).map_or(path, |(archive, _)| archive);
+            fs::metadata(file).ok().map(|meta| if archive_member(path).is_some() {None} else {Some(meta.len())})
+        };
+        let mut files = vec![];
+        let mut hashes = HashMap::new();
+        let mut gone = 0;
+        for (path, size) in self.files {
+            let Some(current) = current_size(&path) else {
+                gone += 1;
+                continue;
+            };
+            if let Some(&(hashed_size, hash, dimensions)) = self.hashes.get(&path) {
+                if current.is_none_or(|current| current == hashed_size) {
+                    hashes.insert(path.clone(), (hashed_size, hash, dimensions));
+                }
+            }
+            files.push((path, current.unwrap_or(size)));
+        }
+        if gone > 0 {
+            progress.on_message(&format!("{} images found by the interrupted run no longer exist and were left out", gone));
+        }
+        Resumed { files, hashes }
+    }
+}
+
+// Rehash a random sample of cache hits, replacing and invalidating any whose stored hash no longer matches.
+// Returns the number of sampled entries that were stale.
+pub fn verify_cached_hashes(
+    hits: &mut [(String, Dhash, Dimensions)], cache: &mut HashCache, sample_size: usize, context: &RunContext, progress: &dyn Progress,
+    options: HashOptions,
+) -> usize {
+    let mut stale = 0;
+    let mut rng = rand::thread_rng();
+    let sample_size = std::cmp::min(sample_size, hits.len());
+
+    for index in rand::seq::index::sample(&mut rng, hits.len(), sample_size) {
+        let hit = &mut hits[index];
+        match hash_image(&hit.0, options, context, progress) {
+            Ok((hash, dimensions)) if hash.to_u64() != hit.1.to_u64() || dimensions != hit.2 => {
+                progress.on_warning(&format!("cached hash for '{}' is stale, rehashed it", hit.0));
+                cache.insert(&hit.0, hash, dimensions);
+                hit.1 = hash;
+                hit.2 = dimensions;
+                stale += 1;
+            },
+            Ok(_) => {},
+            Err(why) => {
+                progress.on_warning(&format!("could not rehash '{}' to verify the cache: {}", hit.0, why));
+                cache.remove(&hit.0);
+                stale += 1;
+            },
+        }
+    }
+
+    stale
+}
+
+pub fn get_total_size_of_files(images: &[DirEntry]) -> io::Result<u64> {
+    let mut total: u64 = 0;
+
+    for im in images {
+        total += fs::metadata(im.path())?.len();
+    }
+
+    Ok(total)
+}
+
+// An original image together with every duplicate that was matched to it
+pub struct DuplicateGroup<V> {
+    pub original: V,
+    pub also_kept: Vec<V>,  // the runners-up --keep-best keeps alongside the original, best first
+    pub duplicates: Vec<V>,
+    pub reason: String,  // why the original was kept over the duplicates
+    pub first_hash: u64,  // hash of the member the group was started with, which every other was matched against
+}
+
+impl<V> DuplicateGroup<V> {
+    // Never empty, a group always has its original
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        1 + self.also_kept.len() + self.duplicates.len()
+    }
+}
+
+impl<V: IsBetterQual> DuplicateGroup<V> {
+    // Keep the best `count` members instead of just the original, moving the best duplicates to `also_kept`
+    pub fn keep_best(&mut self, count: usize, rules: &QualityRules) {
+        self.duplicates.sort_by(|a, b| match a.partial_cmp(b, rules) {
+            Some(verdict) if verdict.better => std::cmp::Ordering::Less,
+            Some(_) => std::cmp::Ordering::Greater,
+            None => std::cmp::Ordering::Equal,
+        });
+        let rest = self.duplicates.split_off(std::cmp::min(count.saturating_sub(1), self.duplicates.len()));
+        self.also_kept = std::mem::replace(&mut self.duplicates, rest);
+    }
+}
+
+impl DuplicateGroup<ImageInfo> {
+    // How many bits a member's hash was from the hash it was matched at, 0 for a copy that hashes the same.
+    // That is the group's first member, not the original: a better image found later may have replaced it.
+    pub fn distance(&self, member: &ImageInfo) -> u32 {
+        (member.hash ^ self.first_hash).count_ones()
+    }
+
+    // Distance of the duplicate furthest from the original
+    pub fn max_distance(&self) -> u32 {
+        self.duplicates.iter().map(|dup| self.distance(dup)).max().unwrap_or(0)
+    }
+
+    // Order the duplicates nearest the hash they were matched at first, ties by path
+    pub fn sort_by_distance(&mut self) {
+        let first = self.first_hash;
+        self.duplicates.sort_by_cached_key(|dup| ((dup.hash ^ first).count_ones(), dup.path.clone()));
+    }
+}
+
+// Longest side members are compared at for --prefer ssim, smaller groups are compared at their smallest
+// member's size
+const SSIM_MAX_SIDE: u32 = 512;
+
+// Side of the square windows SSIM is measured over
+const SSIM_WINDOW: usize = 8;
+
+// Largest group --prefer ssim decodes, unless --max-group-size allows more
+pub const SSIM_MAX_GROUP: usize = 32;
+
+// Structural similarity of two equally sized luma images, averaged over windows of SSIM_WINDOW pixels.
+// 1 means identical; blur, noise and compression artifacts pull it down.
+fn ssim(a: &[f32], b: &[f32], width: usize, height: usize) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..height.saturating_sub(SSIM_WINDOW - 1)).step_by(SSIM_WINDOW / 2) {
+        for left in (0..width.saturating_sub(SSIM_WINDOW - 1)).step_by(SSIM_WINDOW / 2) {
+            let pixels = || (top..top + SSIM_WINDOW)
+                .flat_map(move |y| (left..left + SSIM_WINDOW).map(move |x| y * width + x));
+            let n = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+            let mean_a = pixels().map(|i| a[i] as f64).sum::<f64>() / n;
+            let mean_b = pixels().map(|i| b[i] as f64).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covar) = (0.0, 0.0, 0.0);
+            for i in pixels() {
+                let (da, db) = (a[i] as f64 - mean_a, b[i] as f64 - mean_b);
+                var_a += da * da;
+                var_b += db * db;
+                covar += da * db;
+            }
+            let (var_a, var_b, covar) = (var_a / n, var_b / n, covar / n);
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {1.0} else {total / windows as f64}
+}
+
+// For --prefer ssim: decode every member of the group at a common size, take the per-pixel median of
+// them as a reference, and make the member most similar to it the original. Copies degraded in their
+// own way (compression, resizing, noise) each stray from the median, the least degraded one the least.
+// Returns false, leaving the group as it was, if a member can't be decoded again; groups of two have
+// nothing to outvote either member and are left as they are too. Only members that rank as high as the
+// original by the rules (--reference, --prefer-name, --remove-from) can take its place.
+pub fn elect_by_ssim(
+    group: &mut DuplicateGroup<ImageInfo>, rules: &QualityRules, context: &RunContext, progress: &dyn Progress, options: HashOptions
+) -> bool {
+    if group.duplicates.len() < 2 {return true;}
+    let standing = rules.standing(&group.original);
+    // The original ranks highest, so only those ranking the same could replace it
+    if !group.duplicates.iter().any(|dup| rules.standing(dup) == standing) {return true;}
+    let members: Vec<&ImageInfo> = std::iter::once(&group.original).chain(&group.duplicates).collect();
+    let Ok(images) = members.iter()
+        .map(|info| open_oriented(&info.path, options, context, progress))
+        .collect::<Result<Vec<DynamicImage>, DedupError>>() else { return false };
+
+    let width = images.iter().map(|image| image.width()).min().unwrap_or(1);
+    let height = images.iter().map(|image| image.height()).min().unwrap_or(1);
+    let scale = f64::min(1.0, SSIM_MAX_SIDE as f64 / width.max(height) as f64);
+    let (width, height) = (((width as f64 * scale) as u32).max(1), ((height as f64 * scale) as u32).max(1));
+    let lumas: Vec<Vec<f32>> = images.iter()
+        .map(|image| image.resize_exact(width, height, image::imageops::FilterType::Triangle).to_luma8().into_raw())
+        .map(|pixels| pixels.into_iter().map(f32::from).collect())
+        .collect();
+
+    let mut reference = vec![0.0; lumas[0].len()];
+    let mut column = vec![0.0; lumas.len()];
+    for (i, median) in reference.iter_mut().enumerate() {
+        for (value, luma) in column.iter_mut().zip(&lumas) {
+            *value = luma[i];
+        }
+        column.sort_by(f32::total_cmp);
+        let middle = column.len() / 2;
+        *median = if column.len().is_multiple_of(2) {(column[middle - 1] + column[middle]) / 2.0} else {column[middle]};
+    }
+
+    let scores: Vec<f64> = lumas.iter()
+        .map(|luma| ssim(luma, &reference, width as usize, height as usize))
+        .collect();
+    let best = (0..scores.len())
+        .filter(|&i| rules.standing(members[i]) == standing)
+        .max_by(|&a, &b| scores[a].total_cmp(&scores[b]))
+        .unwrap_or(0);
+    if best > 0 {
+        std::mem::swap(&mut group.original, &mut group.duplicates[best - 1]);
+    }
+    group.reason = format!("closest to the group's median image, SSIM {:.4}", scores[best]);
+    true
+}
+
+// Band `band` of `bands` equal slices of a hash's bits. Hashes within `bands - 1` bits of each other
+// agree on at least one band (pigeonhole), so a table per band finds every near match.
+fn hash_band(hash: u64, band: u32, bands: u32) -> u64 {
+    let (start, end) = (band * 64 / bands, (band + 1) * 64 / bands);
+    (hash >> start) & (u64::MAX >> (64 - (end - start)))
+}
+
+// Group values by key, electing the best quality value of each group as its original. A key is a hash
+// and a part that must be equal; a value joins the first group (in the order they were started) whose
+// first member's hash is within `threshold` bits of its own, and whose original `compatible` accepts it
+// alongside; otherwise it starts a new group. Distances are always measured to a group's first member,
+// so groups can't creep apart through chains of near matches. A threshold of 0 groups equal hashes.
+// Groups come back in the order they were started and duplicates in the order they joined, so the same
+// input always gives the same groups in the same order. `progress` hears about each image as it is placed.
+// The pairs are taken one at a time, so they can be made as they are needed rather than collected first.
+pub fn find_duplicates<I, K, V, F>(
+    kvpairs: I, threshold: u32, rules: &QualityRules, compatible: F, progress: &dyn Progress
+) -> Vec<DuplicateGroup<V>>
+where
+    I: IntoIterator<Item = ((u64, K), V)>,
+    K: Eq + Hash + Clone,
+    V: IsBetterQual,
+    F: Fn(&V, &V) -> bool,
+{
+    let mut groups: Vec<DuplicateGroup<V>> = vec![];
+    let mut first_hashes: Vec<u64> = vec![];
+    // Groups by (key, band, band bits) of their first member; all bits differing matches anything
+    let bands = if threshold >= 64 {0} else {threshold + 1};
+    let slots = |hash: u64| (0..bands.max(1)).map(move |band| (band, if bands == 0 {0} else {hash_band(hash, band, bands)}));
+    let mut group_map: HashMap<(K, u32, u64), Vec<usize>> = HashMap::new();
+    let kvpairs = kvpairs.into_iter();
+    progress.on_action_start("Grouping images", kvpairs.size_hint().0 as u64);
+
+    for ((hash, key), value) in kvpairs {
+        progress.on_action_progress(value.path());
+        let mut candidates: Vec<usize> = slots(hash)
+            .filter_map(|(band, bits)| group_map.get(&(key.clone(), band, bits)))
+            .flatten()
+            .copied()
+            .filter(|&index| (first_hashes[index] ^ hash).count_ones() <= threshold)
+            .collect();
+        candidates.sort_unstable();
+        match candidates.into_iter().find(|&index| compatible(&groups[index].original, &value)) {
+            Some(index) => {  // a matching group already exists at that key
+                let group = &mut groups[index];
+                let Some(verdict) = group.original.partial_cmp(&value, rules) else {
+                    // Without a verdict the first one found stays the original
+                    progress.on_warning(&format!(
+                        "could not compare the quality of '{}' and '{}', keeping '{}' as the original",
+                        value.path(), group.original.path(), group.original.path()
+                    ));
+                    group.duplicates.push(value);
+                    if group.reason.is_empty() {group.reason = String::from("found first, quality could not be compared");}
+                    continue;
+                };
+                if verdict.better { // the current original is better
+                    group.duplicates.push(value);
+                } else { // the new value is better
+                    let old = std::mem::replace(&mut group.original, value);
+                    group.duplicates.push(old);
+                }
+                group.reason = verdict.reason;
+            },
+            None => {
+                for slot in slots(hash) {
+                    group_map.entry((key.clone(), slot.0, slot.1)).or_default().push(groups.len());
+                }
+                first_hashes.push(hash);
+                groups.push(DuplicateGroup { original: value, also_kept: vec![], duplicates: vec![], reason: String::new(), first_hash: hash });
+            },
+        }
+    }
+
+    progress.on_action_done("Grouped images");
+    groups
+}
+
+// A whole run without the command line: scan `dir`, hash every image under it and group those within
+// `threshold` bits, keeping the highest resolution of each group. Nothing is printed, `progress` hears
+// about the work as it happens (NoProgress if nobody is listening), and each call is a run of its own with
+// the default settings. Only groups with duplicates are returned
+pub fn find_duplicate_images(dir: &Path, threshold: u32, progress: &dyn Progress) -> io::Result<Vec<DuplicateGroup<ImageInfo>>> {
+    let scan_options = ScanOptions {
+        all_formats: false, archives: false, excluded: vec![], skip_marked: true, exclude: vec![], include: vec![], max_depth: None,
+        follow_symlinks: false, respect_ignore: false,
+    };
+    let hash_options = HashOptions { trim_borders: false, exif_rotate: true };
+    let context = RunContext::default();
+
+    let mut paths = vec![];
+    for entry in get_images_in_dir(dir, &scan_options, &context, progress)? {
+        let Some(path) = entry.path().to_str().map(String::from) else {continue};
+        let size = fs::metadata(entry.path()).map(|meta| meta.len()).unwrap_or(0);
+        paths.push((path, size));
+    }
+    paths.sort();
+
+    let thread_count = thread::available_parallelism().map_or(1, |count| count.get());
+    let hashes = match hashing_threads(paths.len(), thread_count) {
+        1 => with_decoder_pool(context.decoder_threads, || generate_hashes(paths, 0, 1, &context, progress, hash_options))?,
+        threads => generate_hashes_multithreaded(paths, threads, &context, progress, hash_options)?,
+    };
+
+    let keys = hashes.into_iter()
+        .filter_map(|(path, hash, dimensions)| {
+            let size = image_size(&path).ok()?;
+            let jpeg_quality = estimate_jpeg_quality(&path);
+            Some(((hash.to_u64(), ()), ImageInfo { path, hash: hash.to_u64(), dimensions, size, root: 0, jpeg_quality, metadata: 0, modified: None }))
+        });
+    let rules = QualityRules { prefer: Prefer::Resolution, preferred_name: None, remove_from: None, reference: None, keep_age: None };
+    let mut groups = find_duplicates(keys, threshold, &rules, |_, _| true, progress);
+    groups.retain(|group| !group.duplicates.is_empty());
+    Ok(groups)
+}
+
+const SNAPSHOT_HEADER: &str = "# fast-dedup group snapshot v1";
+
+// Write every group that has duplicates to a snapshot file, one member per line, for a later --baseline run
+pub fn save_snapshot(groups: &[DuplicateGroup<ImageInfo>], file: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(fs::File::create(file)?);
+    writeln!(writer, "{}", SNAPSHOT_HEADER)?;
+
+    for (id, group) in groups.iter().filter(|group| !group.duplicates.is_empty()).enumerate() {
+        writeln!(writer, "{}\t{}\toriginal\t{}", id, hash_to_hex(group.original.hash), group.original.path)?;
+        for dup in &group.duplicates {
+            writeln!(writer, "{}\t{}\tduplicate\t{}", id, hash_to_hex(dup.hash), dup.path)?;
+        }
+    }
+
+    writer.flush()
+}
+
+// Where a path was in a snapshot: the id of its group, and whether it was a duplicate (marked for removal)
+pub struct SnapshotEntry {
+    pub group: usize,
+    pub duplicate: bool,
+}
+
+// Load a snapshot as a map from each grouped path to its place in the snapshot
+pub fn load_snapshot(file: &Path) -> io::Result<HashMap<String, SnapshotEntry>> {
+    let mut groups = HashMap::new();
+
+    for line in BufReader::new(fs::File::open(file)?).lines() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {continue;}
+
+        // group id, hash, role, path (last, so it may contain tabs)
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        if let [id, _, role, path] = fields[..] {
+            if let Ok(group) = id.parse::<usize>() {
+                groups.insert(path.to_string(), SnapshotEntry { group, duplicate: role == "duplicate" });
+            }
+        }
+    }
+
+    Ok(groups)
+}
+
+// How near hashes are looked up when matching within a distance
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum HashIndex {
+    Map,     // buckets of equal hashes, only finds exact matches
+    Bktree,  // a BK-tree, for any distance
+    Lsh,     // the hash split into bands with a table per band, fastest at small distances but heaviest on memory
+}
+
+// A BK-tree over hashes: each child of a node is keyed by its Hamming distance to that node, so by the
+// triangle inequality a query only has to visit children within `distance` of its own distance to the node
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    values: Vec<usize>,  // indices of the values with this hash
+    children: Vec<(u32, usize)>,  // (distance, node)
+}
+
+impl BkTree {
+    pub fn new(hashes: &[u64]) -> BkTree {
+        let mut tree = BkTree { nodes: vec![] };
+        for (i, &hash) in hashes.iter().enumerate() {
+            tree.insert(hash, i);
+        }
+        tree
+    }
+
+    pub fn insert(&mut self, hash: u64, index: usize) {
+        let leaf = BkNode { hash, values: vec![index], children: vec![] };
+        if self.nodes.is_empty() {
+            self.nodes.push(leaf);
+            return;
+        }
+        let mut node = 0;
+        loop {
+            let distance = (self.nodes[node].hash ^ hash).count_ones();
+            if distance == 0 {
+                self.nodes[node].values.push(index);
+                return;
+            }
+            match self.nodes[node].children.iter().find(|&&(edge, _)| edge == distance) {
+                Some(&(_, child)) => node = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(leaf);
+                    self.nodes[node].children.push((distance, child));
+                    return;
+                },
+            }
+        }
+    }
+
+    // Indices of every value whose hash is at most `distance` bits from `hash`
+    fn find(&self, hash: u64, distance: u32) -> Vec<usize> {
+        let mut found = vec![];
+        let mut stack = if self.nodes.is_empty() {vec![]} else {vec![0]};
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            let d = (node.hash ^ hash).count_ones();
+            if d <= distance {
+                found.extend(&node.values);
+            }
+            stack.extend(node.children.iter()
+                .filter(|&&(edge, _)| edge.abs_diff(d) <= distance)
+                .map(|&(_, child)| child));
+        }
+        found
+    }
+}
+
+// Every pair (i, j), i < j, of hashes at most `distance` bits apart, found through the chosen index or
+// without one by comparing every pair, which is quick per pair and so wins at large distances
+fn close_pairs(hashes: &[u64], distance: u32, index: Option<HashIndex>) -> Vec<(usize, usize)> {
+    // A 64 bit hash has too few bits for 65 bands
+    let index = if index == Some(HashIndex::Lsh) && distance >= 64 {None} else {index};
+    let mut pairs: Vec<(usize, usize)> = match index {
+        None => (0..hashes.len()).into_par_iter()
+            .flat_map_iter(|i| (i + 1..hashes.len())
+                .filter(move |&j| (hashes[i] ^ hashes[j]).count_ones() <= distance)
+                .map(move |j| (i, j)))
+            .collect(),
+        Some(HashIndex::Map) => {
+            let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+            for (i, &hash) in hashes.iter().enumerate() {
+                buckets.entry(hash).or_default().push(i);
+            }
+            let mut pairs = vec![];
+            for bucket in buckets.values() {
+                for (a, &i) in bucket.iter().enumerate() {
+                    pairs.extend(bucket[a + 1..].iter().map(|&j| (i, j)));
+                }
+            }
+            pairs
+        },
+        Some(HashIndex::Bktree) => {
+            let tree = BkTree::new(hashes);
+            (0..hashes.len()).into_par_iter()
+                .flat_map_iter(|i| tree.find(hashes[i], distance).into_iter().filter(move |&j| j > i).map(move |j| (i, j)))
+                .collect()
+        },
+        Some(HashIndex::Lsh) => {
+            // Split into distance + 1 bands, so hashes within the distance agree on at least one of them
+            // (pigeonhole) and no match is missed. Candidates sharing a band are then checked in full.
+            let bands = distance + 1;
+            let band_of = |hash: u64, band: u32| hash_band(hash, band, bands);
+            let tables: Vec<HashMap<u64, Vec<usize>>> = (0..bands).into_par_iter()
+                .map(|band| {
+                    let mut table: HashMap<u64, Vec<usize>> = HashMap::new();
+                    for (i, &hash) in hashes.iter().enumerate() {
+                        table.entry(band_of(hash, band)).or_default().push(i);
+                    }
+                    table
+                })
+                .collect();
+            (0..hashes.len()).into_par_iter()
+                .flat_map_iter(|i| {
+                    let mut candidates: Vec<usize> = (0..bands)
+                        .flat_map(|band| tables[band as usize][&band_of(hashes[i], band)].iter().copied())
+                        .filter(|&j| j > i && (hashes[i] ^ hashes[j]).count_ones() <= distance)
+                        .collect();
+                    candidates.sort_unstable();
+                    candidates.dedup();
+                    candidates.into_iter().map(move |j| (i, j))
+                })
+                .collect()
+        },
+    };
+    pairs.sort_unstable();
+    pairs
+}
+
+// A set of visually similar images, for browsing rather than deleting
+pub struct Cluster {
+    pub representative: ImageInfo,
+    pub members: Vec<ImageInfo>,  // the other images, closest to the representative first
+}
+
+// Find the representative of a root in a union-find forest, flattening the path on the way
+pub fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Link every pair of images whose hashes are at most `distance` bits apart into clusters (single linkage,
+// so a chain of small steps can join quite different images). Each cluster's representative is the image
+// closest to all the others. Images that are close to nothing are left out.
+pub fn cluster_images(images: Vec<ImageInfo>, distance: u32, index: Option<HashIndex>) -> Vec<Cluster> {
+    let hashes: Vec<u64> = images.iter().map(|info| info.hash).collect();
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    for (i, j) in close_pairs(&hashes, distance, index) {
+        let (a, b) = (find_root(&mut parents, i), find_root(&mut parents, j));
+        parents[a.max(b)] = a.min(b);
+    }
+    let mut by_root: BTreeMap<usize, Vec<ImageInfo>> = BTreeMap::new();  // roots are the lowest index, so clusters keep input order
+    for (i, info) in images.into_iter().enumerate() {
+        by_root.entry(find_root(&mut parents, i)).or_default().push(info);
+    }
+
+    by_root.into_values()
+        .filter(|members| members.len() > 1)
+        .map(|mut members| {
+            let spread = |a: &ImageInfo| members.iter().map(|b| (a.hash ^ b.hash).count_ones()).sum::<u32>();
+            let centre = (0..members.len())
+                .min_by_key(|&i| (spread(&members[i]), members[i].path.clone()))
+                .unwrap();
+            let representative = members.swap_remove(centre);
+            members.sort_by_key(|info| ((info.hash ^ representative.hash).count_ones(), info.path.clone()));
+            Cluster { representative, members }
+        })
+        .collect()
+}
+
+// The file system operations the delete and copy actions need, so they can be run against something other
+// than the real disk
+pub trait FileOps: Sync {
+    fn is_dir(&self, path: &Path) -> bool;
+    fn exists(&self, path: &Path) -> bool;
+    fn file_size(&self, path: &Path) -> io::Result<u64>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    // Move a file to the user's trash, Unsupported where there is none
+    fn trash(&self, path: &Path) -> io::Result<()>;
+    // Replace `duplicate` with a hard link to `original`, leaving it untouched if that fails
+    fn hard_link(&self, original: &Path, duplicate: &Path) -> io::Result<()>;
+    // Whether the operations only pretend, so summaries say what would have happened
+    fn is_dry_run(&self) -> bool {
+        false
+    }
+}
+
+// The actual file system, used for every real run
+pub struct RealFs;
+
+impl FileOps for RealFs {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        fs::metadata(path).map(|meta| meta.len())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn trash(&self, path: &Path) -> io::Result<()> {
+        trash_file(path)
+    }
+
+    fn hard_link(&self, original: &Path, duplicate: &Path) -> io::Result<()> {
+        // The link is made under a free name next to the duplicate and then renamed over it, which
+        // replaces it in one step
+        let mut name = duplicate.file_name().ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?.to_os_string();
+        name.push(".link");
+        let temp = free_path(self, duplicate.parent().unwrap_or(Path::new(".")), &name);
+        fs::hard_link(original, &temp)?;
+        fs::rename(&temp, duplicate).inspect_err(|_| {let _ = fs::remove_file(&temp);})
+    }
+}
+
+// The platform's trash, which its file manager restores from: the Recycle Bin on Windows, the Finder's
+// trash on macOS and the freedesktop.org trash elsewhere, a file on another drive going to the trash at
+// the top of that drive rather than being copied home
+#[cfg(any(windows, target_os = "macos", all(unix, not(target_os = "ios"), not(target_os = "android"))))]
+fn trash_file(path: &Path) -> io::Result<()> {
+    trash::delete(path).map_err(|why| match why {
+        // Paths are resolved before trashing, which fails without saying why for a file that just disappeared
+        _ if !path.exists() => io::Error::from(io::ErrorKind::NotFound),
+        #[cfg(all(unix, not(target_os = "macos")))]
+        trash::Error::FileSystem { source, .. } => source,
+        why => io::Error::other(why.to_string()),
+    })
+}
+
+#[cfg(not(any(windows, target_os = "macos", all(unix, not(target_os = "ios"), not(target_os = "android")))))]
+fn trash_file(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "this platform has no trash to move files to"))
+}
+
+// Seconds since the unix epoch as YYYY-MM-DDThh:mm:ss in UTC
+fn utc_timestamp(secs: u64) -> String {
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+    // Days since the epoch to a proleptic Gregorian date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 {mp + 3} else {mp - 9};
+    let year = yoe + era * 400 + if month <= 2 {1} else {0};
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+// Stands in for the file system under --dry-run: reads go to the real disk, changes are only written
+// down, and later reads see them, so a planned copy still collides with an earlier one
+#[derive(Default)]
+pub struct DryRun {
+    planned: Mutex<Vec<(&'static str, String, u64)>>,  // what, a description and the bytes of every change, in order
+    files: Mutex<HashMap<PathBuf, u64>>,  // created by a planned copy or move, with their size
+    dirs: Mutex<HashSet<PathBuf>>,
+    removed: Mutex<HashSet<PathBuf>>,
+}
+
+impl DryRun {
+    fn plan(&self, what: &'static str, description: String, bytes: u64) {
+        self.planned.lock().unwrap().push((what, description, bytes));
+    }
+
+    // A line for every planned change and a last one counting each kind, marked so they can't pass for a real run
+    pub fn report(&self) -> Vec<String> {
+        let planned = self.planned.lock().unwrap();
+        let mut lines: Vec<String> = planned.iter().map(|(_, description, _)| format!("[dry run] would {}", description)).collect();
+        let mut counts: Vec<(&str, usize, u64)> = vec![];
+        for &(what, _, bytes) in planned.iter() {
+            match counts.iter_mut().find(|(kind, _, _)| *kind == what) {
+                Some((_, count, total)) => (*count, *total) = (*count + 1, *total + bytes),
+                None => counts.push((what, 1, bytes)),
+            }
+        }
+        let counts: Vec<String> = counts.iter()
+            .map(|&(what, count, bytes)| match what {
+                "create" => format!("create {} directories", count),
+                _ => format!("{} {} files ({})", what, count, HumanBytes(bytes)),
+            })
+            .collect();
+        let counts = if counts.is_empty() {String::from("change nothing")} else {counts.join(", ")};
+        lines.push(format!("Dry run: this run would {}. Nothing has been changed.", counts));
+        lines
+    }
+}
+
+impl FileOps for DryRun {
+    fn is_dir(&self, path: &Path) -> bool {
+        self.dirs.lock().unwrap().contains(path) || (!self.removed.lock().unwrap().contains(path) && path.is_dir())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.files.lock().unwrap().contains_key(path) || self.dirs.lock().unwrap().contains(path)
+            || (!self.removed.lock().unwrap().contains(path) && path.exists())
+    }
+
+    fn file_size(&self, path: &Path) -> io::Result<u64> {
+        if let Some(&size) = self.files.lock().unwrap().get(path) {return Ok(size);}
+        if self.removed.lock().unwrap().contains(path) {return Err(io::Error::from(io::ErrorKind::NotFound));}
+        fs::metadata(path).map(|meta| meta.len())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let size = self.file_size(path)?;
+        self.plan("delete", format!("delete '{}' ({})", path.display(), HumanBytes(size)), size);
+        self.files.lock().unwrap().remove(path);
+        self.removed.lock().unwrap().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let size = self.file_size(from)?;
+        self.plan("copy", format!("copy '{}' to '{}' ({})", from.display(), to.display(), HumanBytes(size)), size);
+        self.files.lock().unwrap().insert(to.to_path_buf(), size);
+        self.removed.lock().unwrap().remove(to);
+        Ok(size)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {return Ok(());}
+        self.plan("create", format!("create the directory '{}'", path.display()), 0);
+        self.dirs.lock().unwrap().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let size = self.file_size(from)?;
+        self.plan("move", format!("move '{}' to '{}'", from.display(), to.display()), size);
+        self.files.lock().unwrap().remove(from);
+        self.removed.lock().unwrap().insert(from.to_path_buf());
+        self.files.lock().unwrap().insert(to.to_path_buf(), size);
+        Ok(())
+    }
+
+    fn trash(&self, path: &Path) -> io::Result<()> {
+        let size = self.file_size(path)?;
+        self.plan("trash", format!("move '{}' to the trash ({})", path.display(), HumanBytes(size)), size);
+        self.files.lock().unwrap().remove(path);
+        self.removed.lock().unwrap().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn hard_link(&self, original: &Path, duplicate: &Path) -> io::Result<()> {
+        let size = self.file_size(duplicate)?;
+        self.file_size(original)?;
+        self.plan("link", format!("replace '{}' with a hard link to '{}' ({})", duplicate.display(), original.display(), HumanBytes(size)), size);
+        Ok(())
+    }
+
+    fn is_dry_run(&self) -> bool {
+        true
+    }
+}
+
+// Extensions of the files phones pair with a photo under the same name, such as the video of a Live Photo
+const COMPANION_EXTENSIONS: [&str; 2] = ["mov", "mp4"];
+
+// The companion files next to an image: same folder and name, a companion extension in either case
+fn companions(ops: &(impl FileOps + ?Sized), image: &Path) -> Vec<PathBuf> {
+    if archive_member(&image.to_string_lossy()).is_some() {return vec![];}
+    let mut found: Vec<PathBuf> = COMPANION_EXTENSIONS.iter()
+        .flat_map(|ext| [ext.to_string(), ext.to_ascii_uppercase()])
+        .map(|ext| image.with_extension(ext))
+        .filter(|companion| ops.exists(companion))
+        .collect();
+    found.dedup();  // case-insensitive file systems find the same file under both spellings
+    found
+}
+
+// Copy the companions of an original next to its copy, renamed to match it
+fn copy_companions(ops: &(impl FileOps + ?Sized), image: &Path, copy: &Path, context: &RunContext, progress: &dyn Progress) {
+    for companion in companions(ops, image) {
+        let Some(ext) = companion.extension() else {continue;};
+        let target = copy.with_extension(ext);
+        if ops.exists(&target) {
+            progress.on_warning(&format!("not copying companion '{}', '{}' already exists", companion.display(), target.display()));
+            continue;
+        }
+        if let Err(why) = context.with_retries("copying", &companion, progress, || ops.copy(&companion, &target)) {
+            let why = format!("could not copy companion '{}': {}", companion.display(), why);
+            progress.on_warning(&why);
+            context.file_failed(why);
+        }
+    }
+}
+
+// The companions of the duplicates that would be deleted, leaving out any that belong to a kept image too
+// (a JPEG and a HEIC of the same shot can share one video)
+pub fn companions_of_duplicates(ops: &(impl FileOps + ?Sized), duplicates: &[String], kept: &[ImageInfo]) -> Vec<String> {
+    let kept: HashSet<PathBuf> = kept.iter().flat_map(|info| companions(ops, Path::new(&info.path))).collect();
+    let mut found: Vec<String> = duplicates.iter()
+        .flat_map(|dup| companions(ops, Path::new(dup)))
+        .filter(|companion| !kept.contains(companion))
+        .filter_map(|companion| companion.to_str().map(String::from))
+        .collect();
+    found.sort_unstable();
+    found.dedup();
+    found
+}
+
+// Files can be moved or deleted by something else between the scan and the action phase. Such a file
+// is skipped with a warning rather than failing the run.
+fn warn_vanished(progress: &dyn Progress, path: &Path, action: &str) {
+    progress.on_warning(&format!("'{}' no longer exists, skipped {} it", path.display(), action));
+}
+
+// Run `work` on every file of an action at once on rayon's threads, as file operations mostly wait on the
+// disk or network. A dry run goes one by one instead, so its report lists the changes in order.
+fn for_each_file<T: Send, R: Send>(ops: &(impl FileOps + ?Sized), items: Vec<T>, work: impl Fn(T) -> R + Sync + Send) -> Vec<R> {
+    if ops.is_dry_run() {
+        items.into_iter().map(work).collect()
+    } else {
+        items.into_par_iter().map(work).collect()
+    }
+}
+
+// Delete the duplicates, or under --trash move them to the trash, falling back to deleting them for good
+// (with a warning) where there is no trash to move them to
+pub fn delete_files(
+    ops: &(impl FileOps + ?Sized), paths: Vec<String>, context: &RunContext, progress: &dyn Progress
+) -> Result<(), DedupError> {
+    enum Outcome { Trashed, Deleted(u64), Vanished, Failed, NotStarted }
+    let trashing = AtomicBool::new(context.trash);
+    let what = if trashing.load(Ordering::Relaxed) {"Moving duplicate images to the trash..."} else {"Deleting duplicate images..."};
+    progress.on_action_start(what, paths.len() as u64);
+
+    let failed_delete = |item: &str, error: io::Error| {
+        if error.kind() == io::ErrorKind::NotFound {
+            warn_vanished(progress, Path::new(item), "deleting");
+            return Outcome::Vanished;
+        }
+        let why = DedupError::DeleteFailed { path: PathBuf::from(item), error };
+        progress.on_warning(&why.to_string());
+        context.file_failed(why);
+        Outcome::Failed
+    };
+    let delete_one = |item: &str| {
+        let path = extended_path(Path::new(item));
+        if ops.is_dir(&path) {
+            // Directories are never hashed, so one showing up here means something upstream is wrong
+            let why = DedupError::IsADirectory { path };
+            progress.on_warning(&format!("skipping {} in the duplicates list, this is a bug", why));
+            context.file_failed(why);
+            return Outcome::Failed;
+        }
+        let size = match ops.file_size(&path) {
+            Ok(size) => size,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                warn_vanished(progress, Path::new(item), "deleting");
+                return Outcome::Vanished;
+            },
+            Err(_) => 0,
+        };
+        if trashing.load(Ordering::Relaxed) {
+            match context.with_retries("trashing", &path, progress, || ops.trash(&path)) {
+                Err(why) if why.kind() == io::ErrorKind::Unsupported => {
+                    // Only the first file to find out says so, the rest are deleted without a word
+                    if trashing.swap(false, Ordering::Relaxed) {
+                        progress.on_warning(&format!("there is no trash to move duplicates to ({}), deleting them permanently instead", why));
+                    }
+                },
+                Ok(()) => {
+                    log_change(ops, context, progress, || format!("Moved '{}' to the trash", item));
+                    record_removal(ops, context, "trashed", item, size);
+                    return Outcome::Trashed;
+                },
+                Err(why) => return failed_delete(item, why),
+            }
+        }
+        match context.with_retries("deleting", &path, progress, || ops.remove_file(&path)) {
+            Ok(()) => {
+                log_change(ops, context, progress, || format!("Deleted '{}'", item));
+                record_removal(ops, context, "deleted", item, size);
+                // Trashed files still take up their space, only these are really freed
+                context.summary.freed.fetch_add(size, Ordering::Relaxed);
+                Outcome::Deleted(size)
+            },
+            Err(error) => failed_delete(item, error),
+        }
+    };
+    let outcomes = for_each_file(ops, paths, |item| {
+        if context.aborted() {return Outcome::NotStarted;}
+        if context.out_of_time() {
+            context.defer();
+            return Outcome::NotStarted;
+        }
+        let outcome = delete_one(&item);
+        context.progress_advance(1);
+        progress.on_action_progress(&item);
+        outcome
+    });
+
+    let (mut trashed, mut deleted, mut freed, mut vanished, mut failed) = (0, 0, 0, 0, 0);
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Trashed => trashed += 1,
+            Outcome::Deleted(size) => (deleted, freed) = (deleted + 1, freed + size),
+            Outcome::Vanished => vanished += 1,
+            Outcome::Failed => failed += 1,
+            Outcome::NotStarted => {},
+        }
+    }
+
+    progress.on_action_done(&match (trashed, deleted) {
+        (0, 0) => String::from("No duplicate images were removed"),
+        (0, _) => String::from("Deleted duplicate images permanently"),
+        (_, 0) => String::from("Moved duplicate images to the trash, they can be restored from there"),
+        (trashed, deleted) => format!(
+            "Moved {} duplicate images to the trash, where they can be restored from, and deleted {} permanently", trashed, deleted
+        ),
+    });
+    if deleted > 0 {
+        let verb = if ops.is_dry_run() {"Would reclaim"} else {"Reclaimed"};
+        progress.on_message(&format!("{} {} across {} files", verb, HumanBytes(freed), deleted));
+    }
+    if vanished > 0 {
+        progress.on_message(&format!("{} duplicates had already disappeared and were skipped", vanished));
+    }
+    if failed > 0 {
+        return Err(DedupError::SomeDeletesFailed { count: failed });
+    }
+
+    Ok(())
+}
+
+// Replace each duplicate with a hard link to its original, so every path still opens the image while its
+// bytes are stored once. Pairs that already are one file are skipped, so a run can be repeated, and so
+// are pairs on different file systems, which no hard link can join.
+pub fn hardlink_files(
+    ops: &(impl FileOps + ?Sized), pairs: Vec<(String, String)>, context: &RunContext, progress: &dyn Progress
+) -> Result<(), DedupError> {
+    let (mut linked, mut already, mut cross_device, mut vanished, mut failed) = (0, 0, 0, 0, 0);
+    let mut freed = 0;
+    progress.on_action_start("Replacing duplicate images with hard links...", pairs.len() as u64);
+
+    for (original, duplicate) in pairs {
+        if context.aborted() {break;}
+        if context.out_of_time() {
+            context.defer();
+            continue;
+        }
+        let (original_path, duplicate_path) = (extended_path(Path::new(&original)), extended_path(Path::new(&duplicate)));
+        let warn_cross_device = || progress.on_warning(&format!(
+            "'{}' is on a different file system than its original '{}', it can't be hard linked and was skipped", duplicate, original
+        ));
+        match (file_identity(&original_path), file_identity(&duplicate_path)) {
+            (Some(a), Some(b)) if a == b => already += 1,
+            (Some((a, _)), Some((b, _))) if a != b => {
+                warn_cross_device();
+                cross_device += 1;
+            },
+            _ => match ops.file_size(&duplicate_path) {
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                    warn_vanished(progress, Path::new(&duplicate), "linking");
+                    vanished += 1;
+                },
+                size => match context.with_retries("linking", &duplicate_path, progress, || ops.hard_link(&original_path, &duplicate_path)) {
+                    Ok(()) => {
+                        log_change(ops, context, progress, || format!("Replaced '{}' with a hard link to '{}'", duplicate, original));
+                        let size = size.unwrap_or(0);
+                        record_removal(ops, context, "linked", &duplicate, size);
+                        context.summary.freed.fetch_add(size, Ordering::Relaxed);
+                        freed += size;
+                        linked += 1;
+                    },
+                    Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+                        warn_cross_device();
+                        cross_device += 1;
+                    },
+                    Err(error) => {
+                        let why = DedupError::LinkFailed { path: PathBuf::from(&duplicate), target: PathBuf::from(&original), error };
+                        progress.on_warning(&why.to_string());
+                        context.file_failed(why);
+                        failed += 1;
+                    },
+                },
+            },
+        }
+        context.progress_advance(1);
+        progress.on_action_progress(&duplicate);
+    }
+
+    progress.on_action_done("Replaced duplicate images with hard links to their originals");
+    if linked > 0 {
+        let verb = if ops.is_dry_run() {"Would reclaim"} else {"Reclaimed"};
+        progress.on_message(&format!("{} {} across {} files", verb, HumanBytes(freed), linked));
+    }
+    if already > 0 {
+        progress.on_message(&format!("{} duplicates already were hard links to their original", already));
+    }
+    if cross_device > 0 {
+        progress.on_message(&format!("{} duplicates were left alone, being on a different file system than their original", cross_device));
+    }
+    if vanished > 0 {
+        progress.on_message(&format!("{} duplicates had already disappeared and were skipped", vanished));
+    }
+    if failed > 0 {
+        return Err(DedupError::SomeLinksFailed { count: failed });
+    }
+
+    Ok(())
+}
+
+// The --log record of every duplicate deleted, trashed or replaced by a hard link. Lines are appended
+// straight to the file as each one goes, never buffered, so even a crash leaves an accurate record.
+pub struct DeletionLog {
+    file: Mutex<fs::File>,
+    originals: Mutex<HashMap<String, String>>,  // the original each duplicate matched
+}
+
+impl DeletionLog {
+    pub fn open(file: &Path) -> io::Result<DeletionLog> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(file)?;
+        Ok(DeletionLog { file: Mutex::new(file), originals: Mutex::new(HashMap::new()) })
+    }
+
+    // Pairs of a duplicate and its original, looked up as the duplicates go
+    pub fn add_originals(&self, pairs: impl IntoIterator<Item = (String, String)>) {
+        self.originals.lock().unwrap().extend(pairs);
+    }
+
+    pub fn original_of(&self, duplicate: &str) -> Option<String> {
+        self.originals.lock().unwrap().get(duplicate).cloned()
+    }
+}
+
+// Append a removed duplicate to the --log as "time, action, size, path, original", tab separated. A file
+// gone unrecorded would defeat the log, so failing to write it stops the run.
+fn record_removal(ops: &(impl FileOps + ?Sized), context: &RunContext, action: &str, path: &str, size: u64) {
+    let Some(log) = &context.deletion_log else {return;};
+    if ops.is_dry_run() {return;}
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let original = log.original_of(path).unwrap_or_else(|| String::from("-"));
+    let line = format!("{}Z\t{}\t{}\t{}\t{}\n", utc_timestamp(now), action, size, path, original);
+    if let Err(why) = log.file.lock().unwrap().write_all(line.as_bytes()) {
+        let _ = context.aborted.set(format!("could not write to the deletion log, stopped so nothing goes unrecorded: {}", why));
+    }
+}
+
+// Under --verbose, report a file that was changed; a dry run lists those in its own report instead
+fn log_change(ops: &(impl FileOps + ?Sized), context: &RunContext, progress: &dyn Progress, line: impl FnOnce() -> String) {
+    if context.verbose && !ops.is_dry_run() {
+        progress.on_message(&line());
+    }
+}
+
+// A path in `dir` for a file called `name` that isn't taken yet, adding "-1", "-2", ... before the
+// extension if needed
+fn free_path(ops: &(impl FileOps + ?Sized), dir: &Path, name: &std::ffi::OsStr) -> PathBuf {
+    free_path_among(ops, dir, name, &HashSet::new())
+}
+
+// Like free_path, also passing over the paths in `claimed`, which are spoken for but may not exist yet
+fn free_path_among(ops: &(impl FileOps + ?Sized), dir: &Path, name: &std::ffi::OsStr, claimed: &HashSet<PathBuf>) -> PathBuf {
+    let name = Path::new(name);
+    let stem = name.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = name.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let mut target = dir.join(name);
+    let mut n = 1;
+    while ops.exists(&target) || claimed.contains(&target) {
+        target = dir.join(format!("{}-{}{}", stem, n, ext));
+        n += 1;
+    }
+    target
+}
+
+// Move damaged images into `dir`, so they can be looked at (or restored from a backup) in one place.
+// Returns how many were moved; a file that can't be moved is left where it is with a warning.
+pub fn quarantine(
+    ops: &(impl FileOps + ?Sized), paths: Vec<String>, dir: &Path, context: &RunContext, progress: &dyn Progress
+) -> io::Result<usize> {
+    ops.create_dir_all(dir)?;
+    let dir = extended_path(dir);
+    // A directory a dry run only plans to create has nothing in it yet
+    let canonical_dir = fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+    let mut moved = 0;
+    let copying = AtomicBool::new(false);
+
+    for item in paths {
+        let path = extended_path(Path::new(&item));
+        // When the quarantine directory is inside a scanned directory, what's in it is found again
+        if fs::canonicalize(&path).is_ok_and(|path| path.starts_with(&canonical_dir)) {continue;}
+        let Some(name) = path.file_name() else {continue;};
+        let target = free_path(ops, &dir, name);
+        match context.with_retries("moving", &path, progress, || move_file(ops, &path, &target, context, progress, &copying)) {
+            Ok(()) => {
+                log_change(ops, context, progress, || format!("Moved '{}' to '{}'", item, target.display()));
+                moved += 1;
+            },
+            Err(why) => {
+                let why = format!("could not move '{}' into quarantine: {}", item, why);
+                progress.on_warning(&why);
+                context.file_failed(why);
+            },
+        }
+    }
+
+    Ok(moved)
+}
+
+// Whether `path` and the place `target` would be created are on different file systems, judged by the
+// device of the nearest part of `target` that exists. None where that can't be told.
+fn crosses_devices(path: &Path, target: &Path) -> Option<bool> {
+    let device = |path: &Path| file_identity(path).map(|(device, _)| device);
+    let existing = target.ancestors().find(|part| part.exists())?;
+    Some(device(path)? != device(existing)?)
+}
+
+// Rename `from` to `to`, or where they are on different file systems (which rename can't bridge) copy it
+// over and delete the original once the copy is known to hold the same bytes. --no-cross-device-moves
+// refuses that instead, since on a large library the copying can take far longer than expected. The
+// first move that has to copy says so, and sets `copying` so later ones don't.
+pub fn move_file(
+    ops: &(impl FileOps + ?Sized), from: &Path, to: &Path, context: &RunContext, progress: &dyn Progress, copying: &AtomicBool
+) -> io::Result<()> {
+    // The device IDs tell most of the time; where they can't, the rename failing does
+    if crosses_devices(from, to) != Some(true) {
+        match ops.rename(from, to) {
+            Err(why) if why.kind() == io::ErrorKind::CrossesDevices => {},
+            result => return result,
+        }
+    }
+    if !context.cross_device_moves {
+        return Err(io::Error::new(
+            io::ErrorKind::CrossesDevices, "the target is on another file system and --no-cross-device-moves is set"
+        ));
+    }
+    if !copying.swap(true, Ordering::Relaxed) {
+        progress.on_warning(&format!(
+            "'{}' is on another file system than '{}', so files are copied there and each original is only deleted \
+            once its copy is checked, which takes far longer than moving (--no-cross-device-moves refuses this)",
+            to.parent().unwrap_or(to).display(), from.display()
+        ));
+    }
+    ops.copy(from, to)?;
+    // A dry run has no copy to check
+    if !ops.is_dry_run() && !files_identical(from, to).unwrap_or(false) {
+        let _ = ops.remove_file(to);
+        return Err(io::Error::other("the copy on the other file system doesn't match the original, which was left in place"));
+    }
+    ops.remove_file(from)
+}
+
+// Move originals into `dir` under their own file name, adding "-1", "-2", ... to the name where it is
+// already taken. With --with-companions their companions move along, renamed to match.
+pub fn move_files_to_dir(
+    ops: &(impl FileOps + ?Sized), images: Vec<ImageInfo>, dir: &Path, context: &RunContext, progress: &dyn Progress
+) -> Result<(), DedupError> {
+    if !ops.is_dir(dir) {return Err(DedupError::NotADirectory { path: dir.to_path_buf() });}
+    let shown_dir = dir.display().to_string();
+    let dir = extended_path(dir);
+    let mut vanished = 0;
+    let mut failed = 0;
+    let copying = AtomicBool::new(false);
+    progress.on_action_start(&format!("Moving original images into '{}'", shown_dir), images.len() as u64);
+
+    for info in images {
+        if context.aborted() {break;}
+        if context.out_of_time() {
+            context.defer();
+            continue;
+        }
+        let path = extended_path(Path::new(&info.path));
+        let Some(name) = path.file_name() else {continue;};
+        if !ops.exists(&path) {
+            warn_vanished(progress, Path::new(&info.path), "moving");
+            vanished += 1;
+        } else {
+            let target = free_path(ops, &dir, name);
+            // Companions are found next to the original, so before it moves
+            let paired = if context.with_companions {companions(ops, &path)} else {vec![]};
+            match context.with_retries("moving", &path, progress, || move_file(ops, &path, &target, context, progress, &copying)) {
+                Ok(()) => {
+                    log_change(ops, context, progress, || format!("Moved '{}' to '{}'", info.path, target.display()));
+                    for companion in paired {
+                        let Some(ext) = companion.extension() else {continue;};
+                        let companion_target = target.with_extension(ext);
+                        if ops.exists(&companion_target) {
+                            progress.on_warning(&format!(
+                                "not moving companion '{}', '{}' already exists", companion.display(), companion_target.display()
+                            ));
+                        } else if let Err(why) = context.with_retries("moving", &companion, progress, || move_file(ops, &companion, &companion_target, context, progress, &copying)) {
+                            let why = format!("could not move companion '{}': {}", companion.display(), why);
+                            progress.on_warning(&why);
+                            context.file_failed(why);
+                        }
+                    }
+                },
+                Err(error) if error.kind() == io::ErrorKind::NotFound && !ops.exists(&path) => {
+                    warn_vanished(progress, Path::new(&info.path), "moving");
+                    vanished += 1;
+                },
+                Err(error) => {
+                    let why = DedupError::MoveFailed { path: PathBuf::from(&info.path), target, error };
+                    progress.on_warning(&why.to_string());
+                    context.file_failed(&why);
+                    failed += 1;
+                },
+            }
+        }
+        context.progress_advance(1);
+        progress.on_action_progress(&info.path);
+    }
+
+    progress.on_action_done(&format!("Moved original images into '{}'", shown_dir));
+    if vanished > 0 {
+        progress.on_message(&format!("{} originals had already disappeared and were skipped", vanished));
+    }
+    if failed > 0 {
+        return Err(DedupError::SomeMovesFailed { count: failed });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink_file(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Lay the duplicate groups out as folders of links for browsing, one folder per group with the members
+// named after their role ("0 original img.jpg", "1 duplicate img_small.jpg", ...). Where symlinks can't
+// be made (Windows without the privilege) the files are copied instead. Archive members are left out.
+// Returns the number of folders written and whether anything had to be copied.
+pub fn write_review_dir(groups: &[DuplicateGroup<ImageInfo>], dir: &Path, progress: &dyn Progress) -> io::Result<(usize, bool)> {
+    // Links from an earlier run would be mixed in with the new ones, or worse, copied through
+    if fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, "it already exists and isn't empty, remove it or choose another"));
+    }
+    fs::create_dir_all(dir)?;
+    mark_output_dir(dir, progress);
+    let mut written = 0;
+    let mut copied = false;
+    for group in groups.iter().filter(|group| !group.duplicates.is_empty()) {
+        written += 1;
+        let folder = dir.join(format!("group-{:04}", written));
+        fs::create_dir_all(&folder)?;
+        let members = std::iter::once((&group.original, "original"))
+            .chain(group.also_kept.iter().map(|info| (info, "also kept")))
+            .chain(group.duplicates.iter().map(|info| (info, "duplicate")));
+        for (i, (info, role)) in members.enumerate() {
+            if archive_member(&info.path).is_some() {continue;}
+            let path = fs::canonicalize(&info.path)?;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let link = folder.join(format!("{} {} {}", i, role, name));
+            if symlink_file(&path, &link).is_err() {
+                fs::copy(&path, &link)?;
+                copied = true;
+            }
+        }
+    }
+    Ok((written, copied))
+}
+
+// Hashes of the images already in `dir`, so originals that were copied there before aren't copied again
+pub fn hashes_in_dir(
+    dir: &Path, scan_options: &ScanOptions, context: &RunContext, progress: &dyn Progress, hash_options: HashOptions
+) -> HashSet<u64> {
+    let images = match get_images_in_dir(&extended_path(dir), scan_options, context, &NoProgress) {
+        Ok(images) => images,
+        Err(why) => {
+            progress.on_warning(&format!("could not read the images already in '{}': {}", dir.display(), why));
+            return HashSet::new();
+        },
+    };
+
+    images.par_iter()
+        .filter_map(|entry| hash_image(entry.path().to_str()?, hash_options, context, progress).ok())
+        .map(|(hash, _)| hash.to_u64())
+        .collect()
+}
+
+// The scan root a path lies under, the innermost one if roots are nested
+pub fn root_index(path: &Path, roots: &[PathBuf]) -> Option<usize> {
+    (0..roots.len())
+        .filter(|&i| path.starts_with(&roots[i]))
+        .max_by_key(|&i| roots[i].components().count())
+}
+
+// A file's path relative to the scan root it was found under. With several roots the root's own name
+// is put in front, so that files from different roots can't land on the same relative path.
+fn mirrored_path(path: &Path, roots: &[PathBuf]) -> Option<PathBuf> {
+    let root = &roots[root_index(path, roots)?];
+    let relative = path.strip_prefix(root).ok()?;
+    match root.file_name() {
+        Some(name) if roots.len() > 1 => Some(Path::new(name).join(relative)),
+        _ => Some(relative.to_path_buf()),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TemplatePiece {
+    Text(String),
+    Name,
+    Ext,
+    Hash,
+    Width,
+    Height,
+    Date,
+}
+
+// A --rename-template, e.g. "{date}/{name}_{width}x{height}.{ext}", parsed into text and placeholders
+#[derive(Clone, Debug)]
+pub struct RenameTemplate(Vec<TemplatePiece>);
+
+impl RenameTemplate {
+    // The destination of an image relative to the target directory
+    fn render(&self, info: &ImageInfo) -> PathBuf {
+        let path = Path::new(&info.path);
+        let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let ext = path.extension().map(|s| s.to_string_lossy()).unwrap_or_default();
+        let mut rendered = String::new();
+
+        for piece in &self.0 {
+            match piece {
+                TemplatePiece::Text(text) => rendered.push_str(text),
+                TemplatePiece::Name => rendered.push_str(&stem),
+                TemplatePiece::Ext => rendered.push_str(&ext),
+                TemplatePiece::Hash => rendered.push_str(&hash_to_hex(info.hash)),
+                TemplatePiece::Width => rendered.push_str(&info.dimensions.0.to_string()),
+                TemplatePiece::Height => rendered.push_str(&info.dimensions.1.to_string()),
+                TemplatePiece::Date => rendered.push_str(&read_exif(&info.path).and_then(|exif| exif_date(&exif))
+                    .map(|(year, month, day)| format!("{}-{}-{}", year, month, day))
+                    .unwrap_or_else(|| "undated".to_string())),
+            }
+        }
+
+        PathBuf::from(rendered)
+    }
+}
+
+// Parse and check a --rename-template, so a typo is reported before anything is hashed
+pub fn parse_rename_template(arg: &str) -> Result<RenameTemplate, String> {
+    let mut pieces = vec![];
+    let mut rest = arg;
+
+    while let Some(open) = rest.find(['{', '}']) {
+        if rest[open..].starts_with('}') {
+            return Err(format!("'{}' has a '}}' without a matching '{{'", arg));
+        }
+        if open > 0 {pieces.push(TemplatePiece::Text(rest[..open].to_string()));}
+        let close = rest[open..].find('}').map(|i| open + i)
+            .ok_or_else(|| format!("'{}' has a '{{' without a matching '}}'", arg))?;
+        pieces.push(match &rest[open + 1..close] {
+            "name" => TemplatePiece::Name,
+            "ext" => TemplatePiece::Ext,
+            "hash" => TemplatePiece::Hash,
+            "width" => TemplatePiece::Width,
+            "height" => TemplatePiece::Height,
+            "date" => TemplatePiece::Date,
+            other => return Err(format!(
+                "'{{{}}}' is not a placeholder, use {{name}}, {{ext}}, {{hash}}, {{width}}, {{height}} or {{date}}", other
+            )),
+        });
+        rest = &rest[close + 1..];
+    }
+    if !rest.is_empty() {pieces.push(TemplatePiece::Text(rest.to_string()));}
+
+    // Every file has to end up inside the target directory, with a file name of its own
+    let path = Path::new(arg);
+    if path.has_root() || path.components().any(|c| !matches!(c, std::path::Component::Normal(_))) {
+        return Err(format!("'{}' must be a relative path inside the target directory, without '..'", arg));
+    }
+    if arg.ends_with(['/', '\\']) || pieces.iter().all(|piece| matches!(piece, TemplatePiece::Text(_))) {
+        return Err(format!("'{}' needs at least one placeholder and must end in a file name", arg));
+    }
+
+    Ok(RenameTemplate(pieces))
+}
+
+// Where the originals copied into the --keep directory go
+pub enum CopyLayout<'a> {
+    Flat,                         // directly into the directory, under their own file name
+    Mirror(&'a [PathBuf]),        // below their path relative to these scan roots
+    Template(&'a RenameTemplate), // at the path the template gives
+}
+
+pub fn copy_files_to_dir(
+    ops: &(impl FileOps + ?Sized), images: Vec<ImageInfo>, dir: &Path, layout: CopyLayout, context: &RunContext,
+    progress: &dyn Progress,
+) -> Result<(), DedupError> {
+    if !ops.is_dir(dir) {return Err(DedupError::NotADirectory { path: dir.to_path_buf() });}
+    enum Outcome { Copied { size: u64, numbered: bool }, Vanished, Failed, NotStarted }
+    let shown_dir = dir.display().to_string();
+    let dir = extended_path(dir);
+    // The names given out so far, as copies running side by side may not have created theirs yet
+    let claimed = Mutex::new(HashSet::new());
+    progress.on_action_start(&format!("Copying original images into '{}'", shown_dir), images.len() as u64);
+
+    let outcomes = for_each_file(ops, images, |info| {
+        if context.aborted() {return Outcome::NotStarted;}
+        if context.out_of_time() {
+            context.defer();
+            return Outcome::NotStarted;
+        }
+        let outcome = match copy_file(ops, &info, &dir, &layout, &claimed, context, progress) {
+            Ok(Some((target, numbered))) => {
+                log_change(ops, context, progress, || format!("Copied '{}' to '{}'", info.path, target.display()));
+                if context.with_companions {
+                    copy_companions(ops, Path::new(&info.path), &target, context, progress);
+                }
+                Outcome::Copied { size: info.size, numbered }
+            },
+            Ok(None) => {
+                warn_vanished(progress, Path::new(&info.path), "copying");
+                Outcome::Vanished
+            },
+            Err(why) => {
+                progress.on_warning(&why.to_string());
+                context.file_failed(&why);
+                Outcome::Failed
+            },
+        };
+        context.progress_advance(1);
+        progress.on_action_progress(&info.path);
+        outcome
+    });
+
+    let (mut copied, mut copied_bytes, mut renamed, mut vanished, mut failed) = (0, 0, 0, 0, 0);
+    for outcome in outcomes {
+        match outcome {
+            Outcome::Copied { size, numbered } => {
+                (copied, copied_bytes) = (copied + 1, copied_bytes + size);
+                if numbered {renamed += 1;}
+            },
+            Outcome::Vanished => vanished += 1,
+            Outcome::Failed => failed += 1,
+            Outcome::NotStarted => {},
+        }
+    }
+
+    progress.on_action_done(&format!("Copied original images into '{}'", shown_dir));
+    if copied > 0 {
+        let verb = if ops.is_dry_run() {"Would copy"} else {"Copied"};
+        progress.on_message(&format!("{} {} across {} originals", verb, HumanBytes(copied_bytes), copied));
+    }
+    if renamed > 0 {
+        progress.on_message(&format!("{} originals were copied under a numbered name, as their own name was already taken", renamed));
+    }
+    if vanished > 0 {
+        progress.on_message(&format!("{} originals had already disappeared and were skipped", vanished));
+    }
+    if failed > 0 {
+        return Err(DedupError::SomeCopiesFailed { count: failed });
+    }
+
+    Ok(())
+}
+
+// Copy one original to where `layout` puts it in `dir`, returning where that is and whether the name had
+// to be numbered because it was taken. Ok(None) means the file no longer exists.
+fn copy_file(
+    ops: &(impl FileOps + ?Sized), info: &ImageInfo, dir: &Path, layout: &CopyLayout, claimed: &Mutex<HashSet<PathBuf>>,
+    context: &RunContext, progress: &dyn Progress,
+) -> Result<Option<(PathBuf, bool)>, DedupError> {
+    let path = extended_path(Path::new(&info.path));
+    if !ops.exists(&path) {return Ok(None);}
+    if ops.is_dir(&path) {return Err(DedupError::IsADirectory { path });}
+    let relative = match layout {
+        CopyLayout::Flat => None,
+        CopyLayout::Mirror(roots) => mirrored_path(&path, roots),
+        CopyLayout::Template(template) => Some(template.render(info)),
+    };
+    let wanted = match relative {
+        Some(relative) => {
+            let wanted = dir.join(relative);
+            if let Some(parent) = wanted.parent() {
+                ops.create_dir_all(parent)
+                    .map_err(|error| DedupError::CopyFailed { path: path.clone(), target: wanted.clone(), error })?;
+            }
+            wanted
+        },
+        None => dir.join(Path::new(path.file_name().unwrap())),
+    };
+    // Originals from different folders often share a name (IMG_0001.jpg), and none may replace another
+    let new_path = {
+        let mut claimed = claimed.lock().unwrap();
+        let new_path = match (wanted.parent(), wanted.file_name()) {
+            (Some(parent), Some(name)) => free_path_among(ops, parent, name, &claimed),
+            _ => wanted.clone(),
+        };
+        claimed.insert(new_path.clone());
+        new_path
+    };
+    match context.with_retries("copying", &path, progress, || ops.copy(&path, &new_path)) {
+        Ok(_) => {
+            let numbered = new_path != wanted;
+            Ok(Some((new_path, numbered)))
+        },
+        Err(error) if error.kind() == io::ErrorKind::NotFound && !ops.exists(&path) => {
+            // Gone while it was being copied, so don't leave the empty target behind
+            let _ = ops.remove_file(&new_path);
+            Ok(None)
+        },
+        Err(error) => Err(DedupError::CopyFailed { path, target: new_path, error }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An image that only has what the original election looks at; no resolution means it can't be compared
+    struct MockImage {
+        name: &'static str,
+        resolution: Option<(u32, u32)>,
+        size: u64,
+    }
+
+    impl MockImage {
+        fn new(name: &'static str, resolution: Option<(u32, u32)>, size: u64) -> MockImage {
+            MockImage { name, resolution, size }
+        }
+    }
+
+    impl IsBetterQual for MockImage {
+        fn path(&self) -> &str {
+            self.name
+        }
+
+        fn partial_cmp(&self, other: &Self, _rules: &QualityRules) -> Option<Verdict> {
+            let (a, b) = (self.resolution?, other.resolution?);
+            let ordering = (a.0 as u64 * a.1 as u64, self.size).cmp(&(b.0 as u64 * b.1 as u64, other.size));
+            Some(Verdict { better: ordering.is_gt(), reason: String::from("larger") })
+        }
+    }
+
+    fn rules(prefer: Prefer) -> QualityRules {
+        QualityRules { prefer, preferred_name: None, remove_from: None, reference: None, keep_age: None }
+    }
+
+    fn group(images: Vec<(u64, MockImage)>, threshold: u32) -> Vec<DuplicateGroup<MockImage>> {
+        let pairs = images.into_iter().map(|(hash, image)| ((hash, ()), image));
+        find_duplicates(pairs, threshold, &rules(Prefer::Resolution), |_, _| true, &NoProgress)
+    }
+
+    fn names(images: &[MockImage]) -> Vec<&str> {
+        images.iter().map(|image| image.name).collect()
+    }
+
+    fn info(path: &str, dimensions: (u32, u32), size: u64) -> ImageInfo {
+        ImageInfo { path: path.to_string(), hash: 0, dimensions, size, root: 0, jpeg_quality: None, metadata: 0, modified: None }
+    }
+
+    #[test]
+    fn identical_keys_keep_the_better_image_in_either_order() {
+        for better_first in [true, false] {
+            let mut images = vec![
+                (7, MockImage::new("small", Some((100, 100)), 10)),
+                (7, MockImage::new("large", Some((200, 200)), 10)),
+            ];
+            if better_first {images.reverse();}
+            let groups = group(images, 0);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].original.name, "large");
+            assert_eq!(names(&groups[0].duplicates), ["small"]);
+        }
+    }
+
+    #[test]
+    fn three_duplicates_form_one_group_around_the_best() {
+        let groups = group(vec![
+            (7, MockImage::new("a", Some((100, 100)), 10)),
+            (7, MockImage::new("b", Some((300, 300)), 10)),
+            (7, MockImage::new("c", Some((200, 200)), 10)),
+        ], 0);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].original.name, "b");
+        assert_eq!(names(&groups[0].duplicates), ["a", "c"]);
+    }
+
+    #[test]
+    fn distinct_hashes_have_no_duplicates() {
+        let groups = group(vec![
+            (0, MockImage::new("a", Some((100, 100)), 10)),
+            (u64::MAX, MockImage::new("b", Some((100, 100)), 10)),
+            (0xFFFF_0000, MockImage::new("c", Some((100, 100)), 10)),
+        ], 4);
+        assert_eq!(groups.len(), 3);
+        assert!(groups.iter().all(|group| group.duplicates.is_empty()));
+    }
+
+    #[test]
+    fn hashes_within_the_threshold_are_grouped() {
+        let groups = group(vec![
+            (0b0000, MockImage::new("a", Some((100, 100)), 10)),
+            (0b0111, MockImage::new("b", Some((200, 200)), 10)),
+            (0b1111, MockImage::new("c", Some((300, 300)), 10)),
+        ], 3);
+        // c is 4 bits from a, the group's first member, so it starts a group of its own
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].original.name, "b");
+        assert_eq!(names(&groups[0].duplicates), ["a"]);
+        assert_eq!(groups[1].original.name, "c");
+    }
+
+    #[test]
+    fn incomparable_images_keep_the_first_one_found() {
+        let groups = group(vec![
+            (7, MockImage::new("first", None, 10)),
+            (7, MockImage::new("second", Some((200, 200)), 10)),
+        ], 0);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].original.name, "first");
+        assert_eq!(names(&groups[0].duplicates), ["second"]);
+        assert_eq!(groups[0].reason, "found first, quality could not be compared");
+    }
+
+    #[test]
+    fn incompatible_images_are_not_grouped() {
+        let pairs = [(7, MockImage::new("a", Some((100, 100)), 10)), (7, MockImage::new("b", Some((100, 100)), 10))]
+            .into_iter().map(|(hash, image)| ((hash, ()), image));
+        let groups = find_duplicates(pairs, 0, &rules(Prefer::Resolution), |_, _| false, &NoProgress);
+        assert_eq!(groups.len(), 2);
+    }
+
+    #[test]
+    fn election_follows_the_preferred_measure_then_the_tie_breaks() {
+        let small_but_heavy = info("/a.jpg", (100, 100), 5000);
+        let large = info("/b.jpg", (200, 200), 1000);
+        let resolution = rules(Prefer::Resolution);
+        assert!(large.partial_cmp(&small_but_heavy, &resolution).unwrap().better);
+        assert!(!small_but_heavy.partial_cmp(&large, &resolution).unwrap().better);
+        assert!(small_but_heavy.partial_cmp(&large, &rules(Prefer::FileSize)).unwrap().better);
+
+        // Equal pixel areas go to the wider image, and identical images to the first path alphabetically
+        let (landscape, portrait) = (info("/z.jpg", (200, 100), 1000), info("/a.jpg", (100, 200), 1000));
+        assert!(landscape.partial_cmp(&portrait, &resolution).unwrap().better);
+        let (first, second) = (info("/a.jpg", (100, 100), 1000), info("/b.jpg", (100, 100), 1000));
+        assert!(first.partial_cmp(&second, &resolution).unwrap().better);
+        assert!(!second.partial_cmp(&first, &resolution).unwrap().better);
+    }
+
+    #[test]
+    fn modification_time_breaks_ties_before_the_path() {
+        let (mut old, mut new) = (info("/b.jpg", (100, 100), 1000), info("/a.jpg", (100, 100), 1000));
+        old.modified = Some(UNIX_EPOCH + Duration::from_secs(1_000));
+        new.modified = Some(UNIX_EPOCH + Duration::from_secs(2_000));
+        let oldest = QualityRules { keep_age: Some(KeepAge::Oldest), ..rules(Prefer::Resolution) };
+        assert!(old.partial_cmp(&new, &oldest).unwrap().better);
+        let newest = QualityRules { keep_age: Some(KeepAge::Newest), ..rules(Prefer::Resolution) };
+        assert!(new.partial_cmp(&old, &newest).unwrap().better);
+        // Without the option the path decides
+        assert!(new.partial_cmp(&old, &rules(Prefer::Resolution)).unwrap().better);
+    }
+
+    #[test]
+    fn reference_and_remove_from_roots_decide_before_quality() {
+        let (mut kept, small) = (info("/lib/a.jpg", (100, 100), 10), info("/in/a.jpg", (200, 200), 10));
+        kept.root = 1;
+        let rules = QualityRules { reference: Some(1), ..rules(Prefer::Resolution) };
+        assert!(kept.partial_cmp(&small, &rules).unwrap().better);
+        let rules = QualityRules { reference: None, remove_from: Some(0), ..rules };
+        assert!(kept.partial_cmp(&small, &rules).unwrap().better);
+    }
+
+    #[test]
+    fn duplicates_sort_by_their_distance_from_the_original() {
+        let hashed = |path, hash| ImageInfo { hash, ..info(path, (100, 100), 10) };
+        let mut group = DuplicateGroup {
+            original: hashed("o", 0),
+            also_kept: vec![],
+            duplicates: vec![hashed("far", 0b111), hashed("same", 0), hashed("b", 0b10), hashed("a", 0b1)],
+            reason: String::new(),
+            first_hash: 0,
+        };
+        group.sort_by_distance();
+        assert_eq!(group.duplicates.iter().map(|dup| dup.path.as_str()).collect::<Vec<_>>(), ["same", "a", "b", "far"]);
+        assert_eq!(group.max_distance(), 3);
+    }
+
+    #[test]
+    fn distances_stay_those_matched_at_when_the_original_is_replaced() {
+        let hashed = |path, hash, side| ImageInfo { hash, ..info(path, (side, side), 10) };
+        let pairs = [hashed("first", 0b0000, 100), hashed("larger", 0b0011, 200), hashed("late", 0b1100, 100)]
+            .into_iter().map(|image| ((image.hash, ()), image));
+        let mut groups = find_duplicates(pairs, 2, &rules(Prefer::Resolution), |_, _| true, &NoProgress);
+        assert_eq!(groups.len(), 1);
+        let group = &mut groups[0];
+        assert_eq!(group.original.path, "larger");
+        assert_eq!(group.distance(&group.original), 2);
+        // "late" is 4 bits from the new original, but was matched 2 bits from the first member
+        group.sort_by_distance();
+        let distances: Vec<(&str, u32)> = group.duplicates.iter().map(|dup| (dup.path.as_str(), group.distance(dup))).collect();
+        assert_eq!(distances, [("first", 0), ("late", 2)]);
+        assert_eq!(group.max_distance(), 2);
+    }
+
+    #[test]
+    fn ignore_rules_follow_gitignore_semantics() {
+        let rules = ["# build output", "target/", "/top.png", "docs/*.png", "*.jpg", "!keep.jpg", ""];
+        let file = IgnoreFile { dir: PathBuf::from("/repo"), rules: rules.iter().filter_map(|line| parse_ignore_line(line)).collect() };
+        let ignored = |path: &str, is_dir| file.verdict(Path::new(path), is_dir) == Some(true);
+        assert!(ignored("/repo/a/target", true));
+        assert!(!ignored("/repo/a/target", false));
+        assert!(ignored("/repo/top.png", false));
+        assert!(!ignored("/repo/a/top.png", false));
+        assert!(ignored("/repo/docs/b.png", false));
+        assert!(!ignored("/repo/a/docs/b.png", false));
+        assert!(ignored("/repo/a/b.jpg", false));
+        assert!(!ignored("/repo/a/keep.jpg", false));
+        assert_eq!(file.verdict(Path::new("/repo/a.png"), false), None);
+    }
+
+    #[test]
+    fn default_extensions_match_in_any_case() {
+        for name in ["a.jpg", "a.JPG", "a.Jpg", "a.jPg", "a.jpeg", "a.JPEG", "a.png", "a.PNG", "a.Png", "a.WebP", "a.tiff"] {
+            assert!(is_image(Path::new(name), false), "{} should be scanned", name);
+        }
+        for name in ["a.txt", "a.jpg.bak", "jpg", "a.ico"] {
+            assert!(!is_image(Path::new(name), false), "{} should not be scanned", name);
+        }
+        assert!(is_image(Path::new("a.ICO"), true));
+    }
+
+    #[test]
+    fn a_group_whose_every_copy_is_doomed_is_refused() {
+        let (original, other) = (info("/no/such/a.jpg", (100, 100), 10), info("/no/such/b.jpg", (100, 100), 10));
+        // The original listed again as its own duplicate, as a path reached twice would be
+        assert!(loses_every_copy(&original, &[], &[original.clone(), other.clone()]).is_some());
+        assert!(loses_every_copy(&original, std::slice::from_ref(&other), std::slice::from_ref(&original)).is_none());
+        assert!(loses_every_copy(&original, &[], &[other]).is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_symlink_to_the_original_is_the_same_copy() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-symlink", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (file, link) = (dir.join("a.jpg"), dir.join("link.jpg"));
+        fs::write(&file, b"image").unwrap();
+        let _ = fs::remove_file(&link);
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        let original = info(file.to_str().unwrap(), (100, 100), 5);
+        let lost = loses_every_copy(&original, &[], &[info(link.to_str().unwrap(), (100, 100), 5)]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(lost.is_some());
+    }
+
+    #[test]
+    fn hashing_never_uses_more_threads_than_images() {
+        assert_eq!(hashing_threads(0, 8), 1);
+        assert_eq!(hashing_threads(10, 8), 1);
+        assert_eq!(hashing_threads(40, 8), 8);
+        assert_eq!(hashing_threads(40, 100), 40);
+        assert_eq!(hashing_threads(40, 1), 1);
+        assert_eq!(hashing_threads(40, 0), 1);
+    }
+
+    #[test]
+    fn threads_are_split_between_roots_by_their_images() {
+        let roots = [PathBuf::from("/a"), PathBuf::from("/b")];
+        let images = |root: &str, count: usize| (0..count).map(|i| (format!("{}/{}.jpg", root, i), 1)).collect::<Vec<_>>();
+        let threads = |parts: Vec<(Vec<(String, u64)>, usize)>| parts.into_iter().map(|(paths, threads)| (paths.len(), threads)).collect::<Vec<_>>();
+
+        assert!(split_per_root(vec![], &roots, 4).is_empty());
+        // Fewer images than threads: no root gets more threads than it has images
+        assert_eq!(threads(split_per_root([images("/a", 1), images("/b", 2)].concat(), &roots, 8)), [(1, 1), (2, 2)]);
+        // One thread still gives every root with images its own
+        assert_eq!(threads(split_per_root([images("/a", 5), images("/b", 5)].concat(), &roots, 1)), [(5, 1), (5, 1)]);
+        assert_eq!(threads(split_per_root([images("/a", 30), images("/b", 10)].concat(), &roots, 4)), [(30, 3), (10, 1)]);
+        assert_eq!(threads(split_per_root(images("/b", 3), &roots, 2)), [(3, 2)]);
+    }
+
+    // Keeps the lines meant for the user
+    #[derive(Default)]
+    struct Messages(Mutex<Vec<String>>);
+
+    impl Progress for Messages {
+        fn on_message(&self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+
+        fn on_warning(&self, line: &str) {
+            self.0.lock().unwrap().push(line.to_string());
+        }
+    }
+
+    #[test]
+    fn a_duplicate_gone_before_deletion_is_skipped_without_failing() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-vanished", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let present = dir.join("present.jpg");
+        fs::write(&present, b"image").unwrap();
+        let paths = vec![dir.join("gone.jpg").display().to_string(), present.display().to_string()];
+
+        let (ops, messages) = (DryRun::default(), Messages::default());
+        let result = delete_files(&ops, paths, &RunContext::default(), &messages);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+        let planned = ops.planned.lock().unwrap();
+        assert_eq!(planned.len(), 1);
+        assert!(planned[0].1.contains("present.jpg"));
+        assert!(messages.0.lock().unwrap().iter().any(|line| line.starts_with("1 duplicates had already disappeared")));
+    }
+
+    #[test]
+    fn ssim_never_demotes_a_reference_library_member() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-ssim", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let clean = image::GrayImage::from_fn(64, 64, |x, y| image::Luma([(x * 4) as u8 ^ (y * 2) as u8]));
+        let mut noisy = clean.clone();
+        for (i, pixel) in noisy.pixels_mut().enumerate() {
+            pixel.0[0] = pixel.0[0].wrapping_add(if i % 3 == 0 {90} else {0});
+        }
+        let path = |name: &str| dir.join(name).display().to_string();
+        noisy.save(path("library.png")).unwrap();
+        clean.save(path("a.png")).unwrap();
+        clean.save(path("b.png")).unwrap();
+        let member = |name: &str, root| ImageInfo { root, ..info(&path(name), (64, 64), 10) };
+        let group = || DuplicateGroup {
+            original: member("library.png", 1),
+            also_kept: vec![],
+            duplicates: vec![member("a.png", 0), member("b.png", 0)],
+            reason: String::new(),
+            first_hash: 0,
+        };
+        let options = HashOptions { trim_borders: false, exif_rotate: false };
+
+        // Without the library, the noisy copy is outvoted by the two clean ones
+        let mut free = group();
+        assert!(elect_by_ssim(&mut free, &rules(Prefer::Ssim), &RunContext::default(), &NoProgress, options));
+        assert_ne!(free.original.path, path("library.png"));
+        for rules in [
+            QualityRules { reference: Some(1), ..rules(Prefer::Ssim) },
+            QualityRules { remove_from: Some(0), ..rules(Prefer::Ssim) },
+            QualityRules { preferred_name: Some(Regex::new("^library").unwrap()), ..rules(Prefer::Ssim) },
+        ] {
+            let mut kept = group();
+            assert!(elect_by_ssim(&mut kept, &rules, &RunContext::default(), &NoProgress, options));
+            assert_eq!(kept.original.path, path("library.png"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn moves_within_one_file_system_are_renames() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-devices", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("a.jpg");
+        fs::write(&file, b"image").unwrap();
+        assert_eq!(crosses_devices(&file, &dir.join("b.jpg")), Some(false));
+        // A target directory that doesn't exist yet is judged by the nearest one that does
+        assert_eq!(crosses_devices(&file, &dir.join("new/deeper/b.jpg")), Some(false));
+        assert_eq!(crosses_devices(&dir.join("gone.jpg"), &dir.join("b.jpg")), None);
+
+        let (messages, copying) = (Messages::default(), AtomicBool::new(false));
+        move_file(&RealFs, &file, &dir.join("b.jpg"), &RunContext::default(), &messages, &copying).unwrap();
+        let moved = fs::read(dir.join("b.jpg")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(moved, b"image");
+        assert!(!copying.load(Ordering::Relaxed) && messages.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn runs_in_one_process_keep_their_failures_to_themselves() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-runs", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+        let clean = image::GrayImage::from_fn(32, 32, |x, y| image::Luma([(x * 8) as u8 ^ (y * 4) as u8]));
+        clean.save(path("good.png")).unwrap();
+        clean.save(path("copy.png")).unwrap();
+        let good = fs::read(path("good.png")).unwrap();
+        fs::write(path("cut.png"), &good[..good.len() - 12]).unwrap();
+        fs::write(path("bad.png"), b"this is not an image, only some text").unwrap();
+        let files = |names: &[&str]| names.iter().map(|&name| (path(name), 1)).collect::<Vec<_>>();
+        let options = HashOptions { trim_borders: false, exif_rotate: false };
+
+        // One run aborts on its first failure, after a truncated image
+        let first = RunContext { on_error: OnError::Abort, ..RunContext::default() };
+        let hashed = generate_hashes(files(&["cut.png", "bad.png", "good.png"]), 0, 1, &first, &NoProgress, options).unwrap();
+        assert!(hashed.is_empty() && first.abort_reason().is_some());
+        assert_eq!(*first.damaged.lock().unwrap(), [path("cut.png"), path("bad.png")]);
+        // The next one starts clean
+        let second = RunContext::default();
+        let hashed = generate_hashes(files(&["good.png", "copy.png"]), 0, 1, &second, &NoProgress, options).unwrap();
+        assert_eq!(hashed.len(), 2);
+        assert!(second.abort_reason().is_none() && second.damaged.lock().unwrap().is_empty());
+        assert_eq!(second.summary.errors.load(Ordering::Relaxed), 0);
+
+        // Whole runs skip the damaged files each time, and find the same group each time
+        let runs: Vec<Vec<usize>> = (0..2)
+            .map(|_| find_duplicate_images(&dir, 0, &NoProgress).unwrap().iter().map(DuplicateGroup::len).collect())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(runs, [[2], [2]]);
+    }
+
+    #[test]
+    fn checkpointed_paths_may_contain_tabs() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-checkpoint", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("checkpoint");
+        let options = HashOptions { trim_borders: false, exif_rotate: true };
+        let files = vec![(String::from("/photos/a\tb.jpg"), 10), (String::from("/photos/c.jpg"), 20)];
+        let checkpoint = Checkpoint::create(&file, &options, &files).unwrap();
+        checkpoint.record("/photos/a\tb.jpg", Dhash::from_u64(0xabc), (30, 40));
+        checkpoint.finish_hashing(false);
+        let resumed = Checkpoint::read(&file, &options);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let resumed = resumed.unwrap();
+        assert_eq!(resumed.files, files);
+        let (size, hash, dimensions) = resumed.hashes["/photos/a\tb.jpg"];
+        assert_eq!((size, hash.to_u64(), dimensions), (10, 0xabc, (30, 40)));
+    }
+
+    #[test]
+    fn only_pngs_without_an_end_chunk_count_as_truncated() {
+        let mut png = vec![];
+        image::GrayImage::from_pixel(16, 16, image::Luma([128])).write_to(&mut io::Cursor::new(&mut png), image::ImageFormat::Png).unwrap();
+        assert_eq!(truncation("/a.png", Some(&png)), None);
+        let mut trailing = png.clone();
+        trailing.extend_from_slice(b"appended metadata");
+        assert_eq!(truncation("/a.png", Some(&trailing)), None);
+        assert_eq!(truncation("/a.png", Some(&png[..png.len() - 12])), Some("truncated, it has no PNG end chunk"));
+        assert_eq!(truncation("/a.png", Some(&png[..10])), Some("truncated, it is too short to be an image"));
+
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-png-end", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("trailing.png");
+        fs::write(&file, &trailing).unwrap();
+        let found = truncation(file.to_str().unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, None);
+    }
+
+    #[test]
+    fn an_estimate_from_fewer_images_than_the_sample_hashes_them_all() {
+        let dir = std::env::temp_dir().join(format!("dedup-test-{}-estimate", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut paths = vec![];
+        for name in ["a.png", "b.png"] {
+            let path = dir.join(name);
+            image::GrayImage::from_fn(16, 16, |x, y| image::Luma([(x * y) as u8])).save(&path).unwrap();
+            paths.push((path.display().to_string(), fs::metadata(&path).unwrap().len()));
+        }
+        let options = HashOptions { trim_borders: false, exif_rotate: false };
+        let estimated = estimate_hashing(&mut paths, 1, &RunContext::default(), &NoProgress, options);
+        fs::remove_dir_all(&dir).unwrap();
+        let (sampled, estimate) = estimated.unwrap();
+        assert_eq!((sampled.len(), paths.len(), estimate), (2, 0, Duration::ZERO));
+        assert!(estimate_hashing(&mut vec![], 1, &RunContext::default(), &NoProgress, options).unwrap().0.is_empty());
+    }
+}
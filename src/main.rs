@@ -1,352 +1,2687 @@
 // cli imports
-use clap::{Arg, Command};
-use indicatif::{HumanBytes, ProgressBar, ProgressStyle, MultiProgress};
+use anyhow::Context;
+use clap::{builder::TypedValueParser, Arg, Command};
+use indicatif::{HumanBytes, HumanCount, ProgressBar, ProgressDrawTarget, ProgressStyle, MultiProgress};
+
+// library imports
+use dedup::*;
 
 // file system imports
-use std::fs::{self, DirEntry};
+use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 // hashing imports
 use fast_dhash::Dhash;
-use image;
-use std::collections::HashMap;
-use std::hash::Hash;
-use imagesize;
+use std::collections::{HashMap, HashSet};
+
+// output imports
+use std::io::{BufRead, BufWriter, Write};
 
 // multithreading imports
-use std::sync::mpsc;
-use std::thread;
+use rayon::prelude::*;
 use std::thread::available_parallelism;
 
+// review imports
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+
 // misc imports
-use std::time::Duration;
+use regex::Regex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Set once from the command line before any work starts
+static QUIET: AtomicBool = AtomicBool::new(false);
+static NOTIFY_LINE: AtomicBool = AtomicBool::new(false);
 
-// Implement partial ordering for image paths
-trait IsBetterQual {
-    fn partial_cmp(&self, other: &Self) -> Option<bool>;
+// A line about how the run is going, which --quiet leaves out. Warnings, errors and the results asked
+// for are printed regardless.
+fn status(line: impl std::fmt::Display) {
+    if !QUIET.load(Ordering::Relaxed) {
+        println!("{}", line);
+    }
 }
-impl IsBetterQual for Path {
-    fn partial_cmp(&self, other: &Self) -> Option<bool> {
-        if !is_image(&self) {return None};
-        if !is_image(&other) {return None};
 
-        let self_size: usize;
-        let other_size: usize;
+// A spinner for work without a known length, which --quiet hides
+fn spinner() -> ProgressBar {
+    if QUIET.load(Ordering::Relaxed) {ProgressBar::hidden()} else {ProgressBar::new_spinner()}
+}
 
-        match imagesize::size(self) {
-            Ok(dim) => {
-                self_size = dim.width * dim.height;
-            }
-            Err(why) => {println!("Error getting size: {:?}", why); return None;}
+// Print how much of a phase was left undone because of the time budget, if any
+fn report_deferred(context: &RunContext, what: &str) {
+    let deferred = context.deferred.swap(0, Ordering::Relaxed);
+    if deferred > 0 {
+        status(format!("Time budget used up, {} {}", deferred, what));
+    }
+}
+
+// Fail the run if it was interrupted, or a file failed under --on-error abort
+fn check_aborted(context: &RunContext) -> anyhow::Result<()> {
+    if context.interrupted() {
+        anyhow::bail!("interrupted, stopped after finishing the files that were being worked on");
+    }
+    match context.abort_reason() {
+        Some(why) => anyhow::bail!("stopped at the first error (--on-error abort): {}", why),
+        None => Ok(()),
+    }
+}
+
+// Under --json-events stdout only carries events, one JSON object per line, for a frontend to follow
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+fn json_events() -> bool {
+    JSON_EVENTS.load(Ordering::Relaxed)
+}
+
+// Write one event, `fields` being the JSON members after its name, flushed so a frontend sees it straight away
+fn emit_event(event: &str, fields: &str) {
+    let mut out = io::stdout().lock();
+    let separator = if fields.is_empty() {""} else {","};
+    let _ = writeln!(out, r#"{{"event":"{}"{}{}}}"#, event, separator, fields).and_then(|_| out.flush());
+}
+
+// Hashing progress is sent at most this often, besides once when all threads are done
+const HASHING_EVENT_INTERVAL: Duration = Duration::from_millis(250);
+
+// The phases in which every file is an event of its own; the others are too busy for that
+const FILE_EVENT_PHASES: [&str; 4] = ["copying", "moving", "linking", "deleting"];
+
+// --json-events in place of the terminal display: hashing progress over all threads, and the start,
+// files and end of every action
+struct EventProgress {
+    phase: Mutex<&'static str>,  // the one last begun, which every action event names
+    hashing: Mutex<HashingTally>,
+    dry_run: bool,  // actions only say what they would do
+}
+
+#[derive(Default)]
+struct HashingTally {
+    workers: usize,
+    files: u64,
+    bytes: u64,
+    total_bytes: u64,
+    last_event: Option<Instant>,
+}
+
+impl HashingTally {
+    fn emit(&mut self) {
+        emit_event("hashing", &format!(r#""files":{},"bytes":{},"total_bytes":{}"#, self.files, self.bytes, self.total_bytes));
+        self.last_event = Some(Instant::now());
+    }
+}
+
+impl Progress for EventProgress {
+    fn on_phase(&self, name: &'static str) {
+        *self.phase.lock().unwrap() = name;
+    }
+
+    fn on_hash_start(&self, _worker: usize, _workers: usize, total_bytes: u64) {
+        let mut tally = self.hashing.lock().unwrap();
+        tally.workers += 1;
+        tally.total_bytes += total_bytes;
+    }
+
+    fn on_hash_progress(&self, _worker: usize, _path: &str, bytes: u64) {
+        let mut tally = self.hashing.lock().unwrap();
+        tally.files += 1;
+        tally.bytes += bytes;
+        if tally.last_event.is_none_or(|last| last.elapsed() >= HASHING_EVENT_INTERVAL) {
+            tally.emit();
         }
+    }
 
-        match imagesize::size(other) {
-            Ok(dim) => {
-                other_size = dim.width * dim.height;
-            }
-            Err(why) => {println!("Error getting size: {:?}", why); return None;}
+    fn on_hash_done(&self, _worker: usize) {
+        let mut tally = self.hashing.lock().unwrap();
+        tally.workers = tally.workers.saturating_sub(1);
+        if tally.workers == 0 {tally.emit();}
+    }
+
+    fn on_action_start(&self, message: &str, total: u64) {
+        emit_event("action_started", &format!(
+            r#""phase":"{}","message":{},"total":{},"dry_run":{}"#, *self.phase.lock().unwrap(), json_string(message), total, self.dry_run
+        ));
+    }
+
+    fn on_action_progress(&self, path: &str) {
+        let phase = *self.phase.lock().unwrap();
+        if FILE_EVENT_PHASES.contains(&phase) {
+            emit_event("file", &format!(r#""phase":"{}","path":{},"dry_run":{}"#, phase, json_string(path), self.dry_run));
         }
+    }
+
+    fn on_action_done(&self, message: &str) {
+        emit_event("action_done", &format!(r#""phase":"{}","message":{}"#, *self.phase.lock().unwrap(), json_string(message)));
+    }
+
+    fn on_message(&self, line: &str) {
+        emit_event("message", &format!(r#""text":{}"#, json_string(line)));
+    }
 
-        return Some(self_size > other_size);
+    fn on_warning(&self, line: &str) {
+        emit_event("warning", &format!(r#""text":{}"#, json_string(line)));
     }
 }
 
-impl IsBetterQual for String {
-    fn partial_cmp(&self, other: &Self) -> Option<bool> {
-        return IsBetterQual::partial_cmp(Path::new(self), Path::new(other));
+// The command line's display: a bar per hashing thread, counting bytes, and one counting files while
+// copying or deleting
+struct TerminalProgress {
+    bars: MultiProgress,
+    hashing: Mutex<HashMap<usize, ProgressBar>>,
+    action: Mutex<Option<ProgressBar>>,
+    byte_style: ProgressStyle,
+    file_style: ProgressStyle,
+}
+
+impl TerminalProgress {
+    fn new() -> Result<Self, indicatif::style::TemplateError> {
+        Ok(TerminalProgress {
+            bars: if QUIET.load(Ordering::Relaxed) {MultiProgress::with_draw_target(ProgressDrawTarget::hidden())} else {MultiProgress::new()},
+            hashing: Mutex::new(HashMap::new()),
+            action: Mutex::new(None),
+            byte_style: ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>10}/{total_bytes:10} ({eta}) {msg}",
+            )?
+            .progress_chars("=>-"),
+            file_style: ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>10}/{len:10} ({eta}) {msg}",
+            )?
+            .progress_chars("=>-"),
+        })
     }
 }
 
-// Check if a given path points to an image file
-fn is_image(path: &Path) -> bool {
-    let ext = path.extension();
-    if !ext.is_none() {
-        match ext.unwrap().to_str() {
-            Some("jpg") => true,
-            Some("jpeg") => true,
-            Some("png") => true,
-            Some("JPG") => true,
-            Some("JPEG") => true,
-            Some("PNG") => true,
-            _ => false
+impl Progress for TerminalProgress {
+    fn on_hash_start(&self, worker: usize, workers: usize, total_bytes: u64) {
+        let bar = self.bars.add(ProgressBar::new(total_bytes).with_style(self.byte_style.clone()));
+        if workers == 1 {
+            bar.set_message("Generating hashes");
+        } else {
+            bar.set_message(format!("Generating hashes, thread #{}", worker + 1));
         }
-    } else {
-        return false
-    }
-}
-
-// Index the root directory for all image files
-fn get_images_in_dir(dir: &Path) -> io::Result<Vec<DirEntry>> {
-    let mut image_paths: Vec<DirEntry> = vec![];
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                match get_images_in_dir(&path) {
-                        Err(why) => println!("! {:?}", why.kind()),
-                        Ok(paths) => for ent in paths {
-                            image_paths.push(ent)
-                        },
-                    }
-            } else {
-                if is_image(&entry.path()){
-                    image_paths.push(entry)
-                }
-            }
+        self.hashing.lock().unwrap().insert(worker, bar);
+    }
+
+    // Advancing by each file's size, so big files don't look like a stall
+    fn on_hash_progress(&self, worker: usize, _path: &str, bytes: u64) {
+        if let Some(bar) = self.hashing.lock().unwrap().get(&worker) {
+            bar.inc(bytes);
         }
     }
 
-    return Ok(image_paths);
-}
+    fn on_hash_done(&self, worker: usize) {
+        if let Some(bar) = self.hashing.lock().unwrap().remove(&worker) {
+            bar.finish_with_message("Done!");
+        }
+    }
 
-fn get_splits<T: Sized + Clone>(big_vec: Vec<T>, count: usize) -> Vec<Vec<T>> {
-    let mut splits = vec![];
-    let r = big_vec.len() % count;
-    let d = big_vec.len() / count;  // len = d * count + r
+    fn on_action_start(&self, message: &str, total: u64) {
+        let bar = self.bars.add(ProgressBar::new(total).with_style(self.file_style.clone()));
+        bar.set_message(message.to_string());
+        *self.action.lock().unwrap() = Some(bar);
+    }
 
-    for i in 0..r {
-        let mut split = vec![];
-        for j in i * (d + 1) .. (i + 1) * (d + 1) {
-            split.push(big_vec[j].clone());
+    fn on_action_progress(&self, _path: &str) {
+        if let Some(bar) = &*self.action.lock().unwrap() {
+            bar.inc(1);
         }
-        splits.push(split);
     }
 
-    for i in r..count {
-        let mut split = vec![];
-        for j in r + i * d .. r + (i + 1) * d {
-            split.push(big_vec[j].clone());
+    fn on_action_done(&self, message: &str) {
+        if let Some(bar) = self.action.lock().unwrap().take() {
+            bar.finish_with_message(message.to_string());
         }
-        splits.push(split);
-    } 
+    }
+
+    fn on_message(&self, line: &str) {
+        // Hidden bars (output isn't a terminal) swallow their println
+        if QUIET.load(Ordering::Relaxed) {return;}
+        if self.bars.is_hidden() {println!("{}", line);} else {self.bars.suspend(|| println!("{}", line));}
+    }
+
+    fn on_warning(&self, line: &str) {
+        self.bars.suspend(|| eprintln!("Warning: {}", line));
+    }
+}
+
+// For what is said once the display is gone: messages as status lines, and warnings
+struct PlainProgress;
 
-    return splits;
+impl Progress for PlainProgress {
+    fn on_message(&self, line: &str) {
+        status(line);
+    }
+
+    fn on_warning(&self, line: &str) {
+        eprintln!("Warning: {}", line);
+    }
+}
+
+// The modification time tie-break asked for with --keep-oldest or --keep-newest, if any
+fn keep_age(m: &clap::ArgMatches) -> Option<KeepAge> {
+    if m.get_flag("KeepOldest") {
+        Some(KeepAge::Oldest)
+    } else if m.get_flag("KeepNewest") {
+        Some(KeepAge::Newest)
+    } else {
+        None
+    }
+}
+
+// Parse a --threads value, "auto" being 0, rejecting anything that isn't a non-negative whole number with a readable message
+fn parse_thread_count(arg: &str) -> Result<usize, String> {
+    if arg.trim().eq_ignore_ascii_case("auto") {return Ok(0);}
+    arg.trim().parse()
+        .map_err(|_| format!("'{}' is not a thread count, use a whole number like 4, or 0 or auto to use one thread per CPU", arg))
+}
+
+// Parse a number of bytes, optionally with a binary unit ("4096", "100K", "5M", "1G", "2GiB")
+fn parse_byte_size(arg: &str) -> Result<u64, String> {
+    let arg = arg.trim();
+    let digits = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let unit: u64 = match arg[digits..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        _ => return Err(format!("'{}' is not a size, use a number of bytes with an optional unit (e.g. 4096, 100K or 5M)", arg)),
+    };
+    arg[..digits].parse::<u64>().ok()
+        .and_then(|count| count.checked_mul(unit))
+        .ok_or_else(|| format!("'{}' is not a size, use a number of bytes with an optional unit (e.g. 4096, 100K or 5M)", arg))
+}
+
+// Parse a duration with a unit suffix ("90s", "30m", "12h", "30d", "2w"), None if there is no unit
+fn parse_duration(arg: &str) -> Option<Result<Duration, String>> {
+    let unit_secs = match arg.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        Some('w') => 7 * 24 * 60 * 60,
+        _ => return None
+    };
+    Some(arg[..arg.len() - 1].parse::<u64>()
+        .map(|count| Duration::from_secs(count * unit_secs))
+        .map_err(|_| format!("'{}' is not a valid duration (try e.g. '30d' or '12h')", arg)))
 }
 
-fn generate_hashes(images: Vec<String>, bar: ProgressBar) -> io::Result<Vec<(String, Dhash)>> {
-    let mut hashes: Vec<(String, Dhash)> = vec![];
+fn parse_time_budget(arg: &str) -> Result<Duration, String> {
+    parse_duration(arg.trim())
+        .unwrap_or_else(|| Err(format!("'{}' is not a duration, give it a unit (e.g. '90m' or '2h')", arg)))
+}
 
-    for im in images {
-        let im_file = image::open(Path::new(&im));
-        if let Ok(im_file) = im_file {
-            hashes.push((im, Dhash::new(&im_file)));
-        } 
+// Parse an age bound, either a duration back from now ("30d", "12h", "2w") or a date ("2023-06-01")
+fn parse_time_bound(arg: &str) -> Result<SystemTime, String> {
+    let arg = arg.trim();
 
-        bar.inc(1);
+    if let Some(duration) = parse_duration(arg) {
+        return SystemTime::now().checked_sub(duration?)
+            .ok_or_else(|| format!("'{}' reaches too far into the past", arg));
     }
 
-    bar.finish_with_message("Done!");
+    let parts: Vec<&str> = arg.split('-').collect();
+    let date: Option<(i64, u32, u32)> = match parts[..] {
+        [y, m, d] => match (y.parse(), m.parse(), d.parse()) {
+            (Ok(y), Ok(m), Ok(d)) if (1..=12).contains(&m) && (1..=31).contains(&d) => Some((y, m, d)),
+            _ => None
+        },
+        _ => None
+    };
+    let (y, m, d) = date.ok_or_else(|| format!("'{}' is neither a duration nor a YYYY-MM-DD date", arg))?;
 
-    return Ok(hashes);
+    // Days since the unix epoch for a proleptic Gregorian date (midnight UTC)
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    if days >= 0 {
+        Ok(UNIX_EPOCH + Duration::from_secs(days as u64 * 24 * 60 * 60))
+    } else {
+        Ok(UNIX_EPOCH - Duration::from_secs(days.unsigned_abs() * 24 * 60 * 60))
+    }
 }
 
-fn generate_hashes_multithreaded(paths: Vec<String>, sty: ProgressStyle, thread_count: usize) -> io::Result<Vec<(String, Dhash)>> {
-    let mut hashes: Vec<(String, Dhash)> = vec![];
+// A rough duration, in the unit a person would use for it
+fn rough_duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64();
+    if secs < 90.0 {
+        format!("~{:.0} seconds", secs.max(1.0))
+    } else if secs < 90.0 * 60.0 {
+        format!("~{:.0} minutes", secs / 60.0)
+    } else {
+        format!("~{:.1} hours", secs / 3600.0)
+    }
+}
 
-    let splits = get_splits(paths, thread_count.try_into().unwrap());
+// List the duplicate relationships that are new since the baseline snapshot was taken. A duplicate is
+// preexisting if the baseline already had it in the same group as its current original.
+fn report_new_duplicates(groups: &[DuplicateGroup<ImageInfo>], baseline: &HashMap<String, SnapshotEntry>) {
+    let mut new = 0;
+    let mut preexisting = 0;
 
-    let (tx, rx) = mpsc::channel();
+    for group in groups.iter().filter(|group| !group.duplicates.is_empty()) {
+        let original_group = baseline.get(&group.original.path).map(|entry| entry.group);
+        let (fresh, known): (Vec<&ImageInfo>, Vec<&ImageInfo>) = group.duplicates.iter()
+            .partition(|dup| original_group.is_none() || baseline.get(&dup.path).map(|entry| entry.group) != original_group);
 
-    let m = MultiProgress::new();
-    let mut i = 1;
+        preexisting += known.len();
+        if fresh.is_empty() {continue;}
+        new += fresh.len();
 
-    for split in splits {
-        let new_bar = m.add(ProgressBar::new(split.len().try_into().unwrap()));
-        new_bar.set_style(sty.clone());
-        new_bar.set_message(format!("Generating hashes, thread #{}", i));
-        let tx1 = tx.clone();
-        thread::spawn(move || {
-            let sub_hashes = generate_hashes(split, new_bar).unwrap();
-            for hash in sub_hashes {
-                tx1.send(hash).unwrap();
-            }
-        });
-        i += 1;
+        println!("Original '{}'", group.original.path);
+        for dup in fresh {
+            println!("    [new]      duplicate '{}'", dup.path);
+        }
+        for dup in known {
+            println!("    [existing] duplicate '{}'", dup.path);
+        }
     }
 
-    drop(tx);
+    println!("{} new duplicate relationships, {} preexisting", new, preexisting);
+}
+
+// Compare the files this run would remove against the duplicates recorded in an earlier snapshot and list
+// only the differences, so drift from new or changed files shows up before anything is acted on
+fn report_action_changes(groups: &[DuplicateGroup<ImageInfo>], previous: &HashMap<String, SnapshotEntry>) {
+    let originals: HashSet<&str> = groups.iter().map(|group| group.original.path.as_str()).collect();
+    let current: HashSet<&str> = groups.iter()
+        .flat_map(|group| &group.duplicates)
+        .map(|dup| dup.path.as_str())
+        .collect();
 
-    for received in rx {
-        hashes.push(received);
+    let mut added: Vec<&str> = current.iter().copied()
+        .filter(|path| !previous.get(*path).is_some_and(|entry| entry.duplicate))
+        .collect();
+    let mut dropped: Vec<&str> = previous.iter()
+        .filter(|(path, entry)| entry.duplicate && !current.contains(path.as_str()))
+        .map(|(path, _)| path.as_str())
+        .collect();
+    added.sort_unstable();
+    dropped.sort_unstable();
+
+    for path in &added {
+        println!("[+] would now be removed: '{}'", path);
+    }
+    for path in &dropped {
+        let now = if originals.contains(path) {"now kept as an original"} else {"no longer in any group"};
+        println!("[-] no longer removed: '{}' ({})", path, now);
     }
 
-    return Ok(hashes);
+    println!(
+        "{} files would be removed that weren't before, {} that were no longer would ({} unchanged)",
+        added.len(), dropped.len(), current.len() - added.len()
+    );
+}
+
+// Print a group with its original first, followed by an indented line per duplicate with the distance
+// it was matched at. With `mark_absent`, members that don't exist on this machine are marked, for groups
+// made from loaded hashes.
+// Duplicates matched further than `borderline_above` bits away are listed as borderline
+fn print_group(group: &DuplicateGroup<ImageInfo>, mark_absent: bool, borderline_above: Option<u32>) {
+    let absent = |info: &ImageInfo| if mark_absent && !Path::new(&info.path).exists() {" [absent]"} else {""};
+    println!("Original '{}'{} ({})", group.original.path, absent(&group.original), group.reason);
+    for kept in &group.also_kept {
+        println!(
+            "    also kept '{}'{} ({}x{}, {})",
+            kept.path, absent(kept), kept.dimensions.0, kept.dimensions.1, HumanBytes(kept.size)
+        );
+    }
+    for dup in &group.duplicates {
+        let distance = group.distance(dup);
+        if is_borderline(group, dup, borderline_above) {
+            println!(
+                "    borderline '{}'{} ({}x{}, {}, {} bits apart)",
+                dup.path, absent(dup), dup.dimensions.0, dup.dimensions.1, HumanBytes(dup.size), distance
+            );
+        } else {
+            println!(
+                "    duplicate '{}'{} ({}x{}, {}, {} bits apart)",
+                dup.path, absent(dup), dup.dimensions.0, dup.dimensions.1, HumanBytes(dup.size), distance
+            );
+        }
+    }
 }
 
-fn get_total_size_of_files(images: &[DirEntry]) -> io::Result<u64> {
-    let mut total: u64 = 0;
+// Whether a duplicate was matched more than `borderline_above` bits away, from the group's first member
+// rather than from whichever image has since become the original
+fn is_borderline(group: &DuplicateGroup<ImageInfo>, dup: &ImageInfo, borderline_above: Option<u32>) -> bool {
+    borderline_above.is_some_and(|above| group.distance(dup) > above)
+}
 
-    for im in images {
-        total += im.metadata().unwrap().len();
+// A string as a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
     }
+    quoted.push('"');
+    quoted
+}
 
-    return Ok(total);
+// One duplicate group as a self-contained JSON object on a single line, for --jsonl. Each member has
+// its size, dimensions and hash; kept runners-up and duplicates also the distance they were matched at.
+fn group_json(group: &DuplicateGroup<ImageInfo>, acted_on: bool) -> String {
+    let member = |info: &ImageInfo| format!(
+        r#"{{"path":{},"size":{},"width":{},"height":{},"hash":"{}""#,
+        json_string(&info.path), info.size, info.dimensions.0, info.dimensions.1, hash_to_hex(info.hash)
+    );
+    let others = |members: &[ImageInfo]| members.iter()
+        .map(|info| format!(r#"{},"distance":{}}}"#, member(info), group.distance(info)))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        r#"{{"original":{}}},"reason":{},"also_kept":[{}],"duplicates":[{}],"acted_on":{}}}"#,
+        member(&group.original), json_string(&group.reason), others(&group.also_kept), others(&group.duplicates), acted_on
+    )
 }
 
-fn find_duplicates<'a, K: Eq + Hash + Clone + 'a, V: IsBetterQual + Clone>(kvpairs: Vec<(K, V)>) -> (Vec<V>, Vec<V>) {
+// Group the hashes stored in cache files, perhaps made on other machines, without scanning or decoding
+// anything. Each file is a set of its own, so `root` tells which file an entry came from.
+fn report_loaded_hashes(files: &[&String], threshold: u32, rules: &QualityRules, snapshot: Option<&String>) -> anyhow::Result<()> {
     let mut keys = vec![];
-    let mut values = vec![];
-    for pair in kvpairs {
-        keys.push(pair.0);
-        values.push(pair.1);
-    }
-    let mut originals = vec![];
-    let mut duplicates = vec![];
-    let mut orig_map: HashMap<K, usize> = HashMap::new();
-
-    for i in 0..std::cmp::min(keys.len(), values.len()) {
-        match orig_map.get(&keys[i]) {
-            Some(&val_index) => {  // a value already exists at that key
-                if values[val_index].partial_cmp(&values[i]).unwrap() { // the new value is better
-                    duplicates.push(values[i].clone());
-                    orig_map.insert(keys[i].clone(), val_index);
-                } else { // the old value is better
-                    duplicates.push(values[val_index].clone());
-                    orig_map.insert(keys[i].clone(), i);
-                }
+    let mut options: Option<String> = None;
+
+    for (set, file) in files.iter().enumerate() {
+        let cache = HashCache::read(Path::new(file)).with_context(|| format!("could not load hashes from '{}'", file))?;
+        match &options {
+            Some(first) if !cache.options.is_empty() && *first != cache.options => eprintln!(
+                "Warning: '{}' was hashed with different options ({}) than '{}' ({}), its images may not match",
+                file, cache.options, files[0], first
+            ),
+            None if !cache.options.is_empty() => options = Some(cache.options.clone()),
+            _ => {},
+        }
+        let mut entries: Vec<(String, CacheEntry)> = cache.entries.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (path, entry) in entries {
+            let hash = entry.hash.to_u64();
+            let info = ImageInfo {
+                path, hash, dimensions: entry.dimensions, size: entry.size, root: set, jpeg_quality: None, metadata: 0, modified: None,
+            };
+            keys.push(((hash, ()), info));
+        }
+    }
+
+    let entry_count = keys.len();
+    let groups = find_duplicates(keys, threshold, rules, |_: &ImageInfo, _: &ImageInfo| true, &NoProgress);
+    if let Some(file) = snapshot {
+        save_snapshot(&groups, Path::new(file)).with_context(|| format!("could not write snapshot '{}'", file))?;
+    }
+    let with_duplicates: Vec<&DuplicateGroup<ImageInfo>> = groups.iter().filter(|group| !group.duplicates.is_empty()).collect();
+    for group in &with_duplicates {
+        print_group(group, true, None);
+    }
+    println!(
+        "Found {} groups of duplicates among {} loaded hashes from {} files. Nothing has been changed.",
+        with_duplicates.len(), entry_count, files.len()
+    );
+
+    Ok(())
+}
+
+// Show what --explain compares: the 8x8 grid of each dHash, where a bit is set if that spot is brighter
+// than the one to its right, and a third grid marking the bits that differ
+fn print_hash_grids(a_name: &str, a: u64, b_name: &str, b: u64, threshold: u32) {
+    let cell = |hash: u64, bit: usize| if hash >> bit & 1 == 1 {"██"} else {"··"};
+    println!("A: '{}' ({})", a_name, hash_to_hex(a));
+    println!("B: '{}' ({})", b_name, hash_to_hex(b));
+    println!();
+    println!("{:<18}{:<18}differences", "A", "B");
+    for row in 0..8 {
+        let grid = |hash: u64| (0..8).map(|col| cell(hash, row * 8 + col)).collect::<String>();
+        let diff: String = (0..8).map(|col| if (a ^ b) >> (row * 8 + col) & 1 == 1 {"XX"} else {"  "}).collect();
+        println!("{}", format!("{}  {}  {}", grid(a), grid(b), diff).trim_end());
+    }
+    println!();
+    let distance = (a ^ b).count_ones();
+    println!(
+        "{} of 64 bits differ, so the two images {} (--threshold {})",
+        distance, if distance <= threshold {"are grouped as duplicates"} else {"are not grouped as duplicates"}, threshold
+    );
+}
+
+const DEFAULT_LIKE_DISTANCE: u32 = 10;
+
+// List the images within `distance` bits of the reference hash of --like, the most similar first.
+// The reference itself is left out if it was part of the scan.
+fn print_similar(file: &str, reference: u64, images: Vec<ImageInfo>, distance: u32, image_count: usize) {
+    let itself = fs::canonicalize(file).ok();
+    let mut similar: Vec<(u32, ImageInfo)> = images.into_iter()
+        .filter(|info| itself.is_none() || fs::canonicalize(&info.path).ok() != itself)
+        .map(|info| ((info.hash ^ reference).count_ones(), info))
+        .filter(|&(bits, _)| bits <= distance)
+        .collect();
+    similar.sort_by(|(a_bits, a), (b_bits, b)| a_bits.cmp(b_bits).then_with(|| a.path.cmp(&b.path)));
+    for (bits, info) in &similar {
+        println!(
+            "'{}' ({} bits apart, {}x{}, {})",
+            info.path, bits, info.dimensions.0, info.dimensions.1, HumanBytes(info.size)
+        );
+    }
+    println!(
+        "Found {} of {} images within {} bits of '{}'. Nothing has been changed.",
+        similar.len(), image_count, distance, file
+    );
+}
+
+// Print each cluster with its representative first, followed by an indented line per similar image
+fn print_clusters(clusters: &[Cluster], image_count: usize, distance: u32) {
+    for cluster in clusters {
+        println!("Cluster of {} around '{}'", cluster.members.len() + 1, cluster.representative.path);
+        for member in &cluster.members {
+            let bits = (member.hash ^ cluster.representative.hash).count_ones();
+            println!("    similar '{}' ({} bits apart)", member.path, bits);
+        }
+    }
+    let clustered: usize = clusters.iter().map(|cluster| cluster.members.len() + 1).sum();
+    println!(
+        "Found {} clusters of similar images within {} bits, covering {} of {} images. Nothing has been changed.",
+        clusters.len(), distance, clustered, image_count
+    );
+}
+
+// What the reviewer decided for one group: which member to keep, and whether to act on the group at all
+struct Decision {
+    keep: usize,  // 0 is the original, then the duplicates in order
+    marked: bool,
+}
+
+// Let the user step through the groups `reviewable` accepts, pick the member to keep in each and mark
+// which groups to act on. Nothing is applied until they confirm; None means they quit without changes.
+// Unmarked groups come back split into single image groups, so every member is left alone.
+fn review_groups(
+    groups: Vec<DuplicateGroup<ImageInfo>>, action: &str, reviewable: impl Fn(&DuplicateGroup<ImageInfo>) -> bool
+) -> io::Result<Option<Vec<DuplicateGroup<ImageInfo>>>> {
+    let indices: Vec<usize> = (0..groups.len())
+        .filter(|&i| !groups[i].duplicates.is_empty() && reviewable(&groups[i]))
+        .collect();
+    if indices.is_empty() {return Ok(Some(groups));}
+    let mut decisions: Vec<Decision> = indices.iter().map(|_| Decision { keep: 0, marked: true }).collect();
+
+    let mut stdout = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
+    let confirmed = review_loop(&groups, &indices, &mut decisions, action);
+    // The terminal is restored even if reading keys failed, before that error is passed on
+    let _ = execute!(stdout, cursor::Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+    if !confirmed? {return Ok(None);}
+
+    let mut decided: HashMap<usize, Decision> = indices.into_iter().zip(decisions).collect();
+    let mut reviewed = vec![];
+    for (i, group) in groups.into_iter().enumerate() {
+        let Some(decision) = decided.remove(&i) else {
+            reviewed.push(group);
+            continue;
+        };
+        let reason = if decision.keep == 0 {group.reason} else {String::from("chosen in review")};
+        let mut members = vec![group.original];
+        members.extend(group.duplicates);
+
+        if decision.marked {
+            let original = members.remove(decision.keep);
+            reviewed.push(DuplicateGroup { original, also_kept: vec![], duplicates: members, reason, first_hash: group.first_hash });
+        } else {
+            reviewed.extend(members.into_iter().map(|original| DuplicateGroup {
+                first_hash: original.hash, original, also_kept: vec![], duplicates: vec![], reason: String::from("left alone in review"),
+            }));
+        }
+    }
+
+    Ok(Some(reviewed))
+}
+
+// Handle keys until the user applies (true) or quits (false)
+fn review_loop(
+    groups: &[DuplicateGroup<ImageInfo>], indices: &[usize], decisions: &mut [Decision], action: &str
+) -> io::Result<bool> {
+    let (mut current, mut selected) = (0, 0);
+    loop {
+        draw_review(&groups[indices[current]], current, decisions, selected, action)?;
+        let Event::Key(key) = event::read()? else {continue;};
+        if key.kind != KeyEventKind::Press {continue;}
+
+        let members = groups[indices[current]].len();
+        match key.code {
+            KeyCode::Right | KeyCode::Char('n') if current + 1 < indices.len() => {
+                current += 1;
+                selected = decisions[current].keep;
             },
-            _ => {
-                orig_map.insert(keys[i].clone(), i);
+            KeyCode::Left | KeyCode::Char('p') if current > 0 => {
+                current -= 1;
+                selected = decisions[current].keep;
             },
+            KeyCode::Down | KeyCode::Char('j') => selected = (selected + 1).min(members - 1),
+            KeyCode::Up | KeyCode::Char('k') => selected = selected.saturating_sub(1),
+            KeyCode::Char(' ') => decisions[current].keep = selected,
+            KeyCode::Char('m') => decisions[current].marked = !decisions[current].marked,
+            KeyCode::Char('a') => return Ok(true),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(false),
+            _ => {},
         }
     }
+}
+
+fn draw_review(
+    group: &DuplicateGroup<ImageInfo>, current: usize, decisions: &[Decision], selected: usize, action: &str
+) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let decision = &decisions[current];
+    let marked = decisions.iter().filter(|decision| decision.marked).count();
+    // Raw mode doesn't translate newlines, so every line ends in an explicit carriage return
+    queue!(stdout, terminal::Clear(terminal::ClearType::All), cursor::MoveTo(0, 0))?;
+    write!(
+        stdout, "Group {} of {} ({} marked), {}\r\n\r\n",
+        current + 1, decisions.len(), marked,
+        if decision.marked {format!("marked: keep one, {}", action)} else {String::from("not marked: left alone")}
+    )?;
+
+    for (i, member) in std::iter::once(&group.original).chain(&group.duplicates).enumerate() {
+        write!(
+            stdout, "{} {} {}x{} {:>10} '{}'{}\r\n",
+            if i == selected {">"} else {" "},
+            if i == decision.keep {"[keep]"} else {"      "},
+            member.dimensions.0, member.dimensions.1, HumanBytes(member.size).to_string(), member.path,
+            if i == 0 {format!(" ({})", group.reason)} else {String::new()},
+        )?;
+    }
+
+    write!(
+        stdout, "\r\n<-/-> previous/next group   up/down select   space keep selected   m mark/unmark group\r\n\
+        a apply all decisions   q quit without changing anything\r\n"
+    )?;
+    stdout.flush()
+}
 
-    for o in orig_map {  // convert hashmap to vector
-        originals.push(values[o.1].clone());
+// Ask about each group `askable` accepts on the terminal before `action` is done to it. Groups that are
+// turned down, or not asked about because input ran out or isn't a terminal, come back split into single
+// image groups, so every member is left alone.
+fn confirm_groups(
+    groups: Vec<DuplicateGroup<ImageInfo>>, action: &str, askable: impl Fn(&DuplicateGroup<ImageInfo>) -> bool
+) -> io::Result<Vec<DuplicateGroup<ImageInfo>>> {
+    let total = groups.iter().filter(|group| !group.duplicates.is_empty() && askable(group)).count();
+    let mut input = io::stdin().lock();
+    // Without someone to answer, nothing is agreed to
+    let mut answer_all = if io::IsTerminal::is_terminal(&input) {None} else {
+        println!("Input is not a terminal, so no group can be confirmed and every group is left alone.");
+        Some(false)
+    };
+    let mut asked = 0;
+    let mut confirmed = vec![];
+
+    for group in groups {
+        if group.duplicates.is_empty() || !askable(&group) {
+            confirmed.push(group);
+            continue;
+        }
+        asked += 1;
+        let go_ahead = match answer_all {
+            Some(answer) => answer,
+            None => {
+                println!("\nGroup {} of {}:", asked, total);
+                for (member, role) in std::iter::once((&group.original, "keep")).chain(group.duplicates.iter().map(|dup| (dup, "dup "))) {
+                    println!("  {} '{}' ({}x{}, {})", role, member.path, member.dimensions.0, member.dimensions.1, HumanBytes(member.size));
+                }
+                loop {
+                    print!("Keep the first one and {}? [y]es, [n]o, [a]ll remaining groups, [q]uit asking and leave the rest: ", action);
+                    io::stdout().flush()?;
+                    let mut line = String::new();
+                    if input.read_line(&mut line)? == 0 {
+                        println!("\nEnd of input, leaving this and the remaining groups alone.");
+                        answer_all = Some(false);
+                        break false;
+                    }
+                    match line.trim().to_ascii_lowercase().as_str() {
+                        "y" | "yes" => break true,
+                        "n" | "no" => break false,
+                        "a" | "all" => {
+                            answer_all = Some(true);
+                            break true;
+                        },
+                        "q" | "quit" => {
+                            answer_all = Some(false);
+                            break false;
+                        },
+                        _ => println!("Please answer y, n, a or q."),
+                    }
+                }
+            },
+        };
+        if go_ahead {
+            confirmed.push(group);
+        } else {
+            confirmed.extend(std::iter::once(group.original).chain(group.also_kept).chain(group.duplicates).map(|original| DuplicateGroup {
+                first_hash: original.hash, original, also_kept: vec![], duplicates: vec![], reason: String::from("left alone at the prompt"),
+            }));
+        }
     }
 
-    return (originals, duplicates);
+    Ok(confirmed)
 }
 
-fn delete_files(paths: Vec<String>) -> io::Result<()> {
-    for item in paths {
-        let path = Path::new(&item);
-        if path.is_dir() {return Err(std::io::Error::new(std::io::ErrorKind::Other, "Can't delete folder"));}
-        if let Err(why) = fs::remove_file(path) {
-            return Err(why);
+// Check the directories files are copied or moved into against the scan roots. One that holds a root
+// would have the run write into the tree it is reading, so that is an error; one inside a root is left
+// out of the scan, so earlier output isn't matched against its own sources. Directories that don't
+// exist yet can't hold anything to scan. With `rescan` (--scan-outputs) nothing is left out.
+fn excluded_output_dirs(outputs: &[(&str, &str)], roots: &[PathBuf], rescan: bool) -> anyhow::Result<Vec<PathBuf>> {
+    let roots: Vec<PathBuf> = roots.iter().filter_map(|root| fs::canonicalize(root).ok()).collect();
+    let mut excluded = vec![];
+    for &(option, dir) in outputs {
+        let Ok(canonical) = fs::canonicalize(dir) else { continue };
+        if let Some(root) = roots.iter().find(|root| root.starts_with(&canonical)) {
+            anyhow::bail!(
+                "the {} directory '{}' contains the scanned directory '{}', choose a directory outside of it",
+                option, dir, root.display()
+            );
+        }
+        if !rescan && roots.iter().any(|root| canonical.starts_with(root)) {
+            status(format!("Not scanning '{}', the {} directory", dir, option));
+            excluded.push(canonical);
         }
     }
+    Ok(excluded)
+}
 
-    return Ok(());
+// Nothing to hash, group or act on, most likely the wrong directory was given or the filters left
+// nothing in, so it fails rather than reporting an empty result. `from_list` when the images were named
+// in a file list rather than found under `roots`.
+fn require_images(paths: &[(String, u64)], roots: &[PathBuf], from_list: bool) -> anyhow::Result<()> {
+    if paths.is_empty() && from_list {
+        anyhow::bail!("no supported image files found in the file list");
+    }
+    if paths.is_empty() {
+        let dirs: Vec<String> = roots.iter().map(|root| format!("'{}'", root.display())).collect();
+        anyhow::bail!("no supported image files found in {}", dirs.join(", "));
+    }
+    Ok(())
 }
 
-fn copy_files_to_dir(paths: Vec<String>, dir: &Path) -> io::Result<()> {
-    if !dir.is_dir() {return Err(std::io::Error::new(std::io::ErrorKind::Other, "'dir' must be a directory"));}
+// What is done with the duplicates found. The action flags exclude each other, so at most one is given.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action<'a> {
+    List,  // no action was given: the groups are only listed and nothing is touched
+    Delete,
+    Hardlink,
+    Keep(&'a str),  // copy the originals into this directory, leaving everything where it is
+    MoveTo(&'a str),  // move the originals into this directory and delete the duplicates
+}
 
-    for item in paths {
-        let path = Path::new(&item);
-        if path.is_dir() {return Err(std::io::Error::new(std::io::ErrorKind::Other, "Can't copy folder"));}
-        let new_path = dir.join(Path::new(path.file_name().unwrap()));
-        let _ = fs::File::create(&new_path).unwrap();
-        if let Err(why) = fs::copy(path, new_path) {
-            return Err(why);
+impl Action<'_> {
+    // What happens to a group once its original is picked, for --interactive and --review
+    fn prompt(&self) -> &'static str {
+        match self {
+            Action::Delete => "delete the others",
+            Action::Hardlink => "replace the others with hard links to it",
+            Action::MoveTo(_) => "move the kept one and delete the others",
+            Action::Keep(_) | Action::List => "copy the kept one",
         }
     }
+}
+
+fn chosen_action(m: &clap::ArgMatches) -> Action<'_> {
+    if m.get_flag("Delete") {
+        Action::Delete
+    } else if m.get_flag("Hardlink") {
+        Action::Hardlink
+    } else if let Some(dir) = m.get_one::<String>("MoveTo") {
+        Action::MoveTo(dir)
+    } else if let Some(dir) = m.get_one::<String>("Keep") {
+        Action::Keep(dir)
+    } else {
+        Action::List
+    }
+}
+
+// Check a directory given to scan, and make it absolute so the paths listed are unambiguous
+fn scan_root(dir: &str) -> anyhow::Result<PathBuf> {
+    match fs::metadata(dir) {
+        Err(why) if why.kind() == io::ErrorKind::NotFound => anyhow::bail!("'{}' does not exist", dir),
+        Err(why) => Err(why).with_context(|| format!("could not read '{}'", dir)),
+        Ok(meta) if !meta.is_dir() => anyhow::bail!("'{}' is not a directory", dir),
+        Ok(_) => fs::canonicalize(dir).with_context(|| format!("could not resolve '{}'", dir)),
+    }
+}
 
-    return Ok(());
+// Check the directories given to scan. With several, one that can't be scanned is reported and left
+// out, as is one inside another (its images would be found twice, as duplicates of themselves); the
+// run only stops if none is left.
+fn scan_roots(dirs: &[&str], context: &RunContext) -> anyhow::Result<Vec<PathBuf>> {
+    if let [dir] = dirs {return Ok(vec![scan_root(dir)?]);}
+    let mut roots: Vec<PathBuf> = vec![];
+    for dir in dirs {
+        match scan_root(dir) {
+            Ok(root) => roots.push(root),
+            Err(why) => {
+                eprintln!("Warning: not scanning '{}': {:#}", dir, why);
+                context.file_failed(format!("could not scan '{}': {:#}", dir, why));
+                context.record_skip(SkipReason::UnreadableDir, Path::new(dir), format!("{:#}", why), &PlainProgress);
+            },
+        }
+    }
+    let mut seen = HashSet::new();
+    roots.retain(|root| seen.insert(root.clone()));
+    let all = roots.clone();
+    roots.retain(|root| match all.iter().find(|other| *other != root && root.starts_with(other)) {
+        Some(outer) => {
+            status(format!("Not scanning '{}' separately, it is inside '{}'", root.display(), outer.display()));
+            false
+        },
+        None => true,
+    });
+    if roots.is_empty() {anyhow::bail!("none of the directories given can be scanned");}
+    Ok(roots)
 }
 
+// The failures listed at the end of a run, so a share that went away doesn't bury the rest of the output
+const MAX_FAILURES_LISTED: usize = 20;
+
 fn main() {
+    let mut context = RunContext::default();
+    let result = run(&mut context);
+    let summary = &context.summary;
+
+    let errors = summary.errors.load(Ordering::Relaxed);
+    if result.is_ok() && errors > 0 {
+        eprintln!("{} files could not be read, decoded, copied, moved or deleted and were skipped:", errors);
+        let failures = summary.failures.lock().unwrap();
+        for why in failures.iter().take(MAX_FAILURES_LISTED) {
+            eprintln!("  {}", why);
+        }
+        if failures.len() > MAX_FAILURES_LISTED {
+            eprintln!("  ... and {} more", failures.len() - MAX_FAILURES_LISTED);
+        }
+    }
+
+    if json_events() {
+        let error = result.as_ref().err().map_or(String::from("null"), |why| json_string(&format!("{:#}", why)));
+        emit_event("finished", &format!(
+            r#""ok":{},"error":{},"scanned":{},"duplicates":{},"freed":{},"errors":{}"#,
+            result.is_ok(), error, summary.scanned.load(Ordering::Relaxed), summary.duplicates.load(Ordering::Relaxed),
+            summary.freed.load(Ordering::Relaxed), summary.errors.load(Ordering::Relaxed)
+        ));
+    }
+    if NOTIFY_LINE.load(Ordering::Relaxed) {
+        if result.is_err() {
+            summary.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        println!("{}", summary.line());
+    }
+
+    // A finished run has no use for its checkpoint, a failed or interrupted one resumes from it
+    if let Some(checkpoint) = &context.checkpoint {
+        if result.is_ok() {checkpoint.remove(&PlainProgress);}
+    }
+
+    // Written even when the run fails, the skips so far may explain why
+    if let Some(report) = &context.skip_report {
+        if let Err(why) = report.save() {
+            eprintln!("Warning: could not write the skipped files report: {}", why);
+        }
+    }
+
+    if let Err(why) = result {
+        eprintln!("Error: {:#}", why);
+        std::process::exit(1);
+    }
+}
+
+// The first Ctrl-C (or SIGTERM) only asks the run to stop between files, so no copy, move or delete is
+// left half done and the messages so far say what was; a second one exits straight away
+fn handle_interrupts(flag: &Arc<AtomicBool>) -> io::Result<()> {
+    for &signal in signal_hook::consts::TERM_SIGNALS {
+        signal_hook::flag::register_conditional_shutdown(signal, 130, Arc::clone(flag))?;
+        signal_hook::flag::register(signal, Arc::clone(flag))?;
+    }
+    Ok(())
+}
+
+fn run(context: &mut RunContext) -> anyhow::Result<()> {
     // get cli arguments
     let m = cli().get_matches();
+    if let Err(why) = handle_interrupts(&context.interrupted) {
+        eprintln!("Warning: could not install the Ctrl-C handler, interrupting will stop the run wherever it is: {}", why);
+    }
+    context.retries = m.get_one::<u32>("Retries").copied().unwrap_or(0);
+    context.on_error = m.get_one::<OnError>("OnError").copied().unwrap_or(OnError::Skip);
+    context.verbose = m.get_flag("Verbose");
+    context.with_companions = m.get_flag("WithCompanions");
+    context.cross_device_moves = !m.get_flag("NoCrossDeviceMoves");
+    context.trash = m.get_flag("Trash");
+    NOTIFY_LINE.store(m.get_flag("NotifyLine"), Ordering::Relaxed);
+    JSON_EVENTS.store(m.get_flag("JsonEvents"), Ordering::Relaxed);
+    // Events take the place of every bar and status line
+    QUIET.store(m.get_flag("Quiet") || json_events(), Ordering::Relaxed);
+    if let Some(&budget) = m.get_one::<Duration>("TimeBudget") {
+        context.deadline = Some(Instant::now() + budget);
+    }
 
-    // Explore the filetree for images
-    let root = Path::new(".");
-    let spin = ProgressBar::new_spinner();
-    spin.set_message("Looking for image files...");
-    spin.enable_steady_tick(Duration::from_millis(50));
+    // Comparing two stored hashes needs no scan at all
+    if let Some(hashes) = m.get_many::<String>("HashDistance") {
+        let hashes: Vec<&String> = hashes.collect();
+        let distance = hex_hamming_distance(hashes[0], hashes[1]).map_err(anyhow::Error::msg)?;
+        println!("{}", distance);
+        return Ok(());
+    }
+    let threshold = m.get_one::<u32>("Threshold").copied().unwrap_or(0);
+    if let Some(files) = m.get_many::<String>("Explain") {
+        let files: Vec<&String> = files.collect();
+        let options = HashOptions { trim_borders: m.get_flag("TrimBorders"), exif_rotate: !m.get_flag("NoExifRotate") };
+        let mut hashes = vec![];
+        for file in &files {
+            let (hash, _) = hash_image(file, options, context, &PlainProgress).with_context(|| format!("could not hash '{}'", file))?;
+            hashes.push(hash.to_u64());
+        }
+        print_hash_grids(files[0], hashes[0], files[1], hashes[1], threshold);
+        return Ok(());
+    }
+    let preference = match m.get_one::<Prefer>("Prefer") {
+        Some(&prefer) => prefer,
+        None if m.get_flag("KeepLargestFile") => Prefer::FileSize,
+        None => Prefer::Resolution,
+    };
 
-    let images = get_images_in_dir(root).unwrap();
-    spin.finish_with_message(format!("Found {} of image files", HumanBytes(get_total_size_of_files(&images).unwrap())));
-    
-    // Progress bar definitions
-    let sty = ProgressStyle::with_template(
-        "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
-    )
-    .unwrap()
-    .progress_chars("=>-");
+    // Loaded hash sets are grouped as they are, nothing is scanned
+    if let Some(files) = m.get_many::<String>("LoadHashes") {
+        let rules = QualityRules {
+            prefer: preference,
+            preferred_name: m.get_one::<Regex>("PreferName").cloned(),
+            remove_from: None,
+            reference: None,
+            keep_age: None,  // loaded hashes have no modification times
+        };
+        let files: Vec<&String> = files.collect();
+        return report_loaded_hashes(&files, threshold, &rules, m.get_one::<String>("SaveGroups"));
+    }
+    if let Some(file) = m.get_one::<String>("ProgressLog") {
+        let log = ProgressLog::open(Path::new(file)).with_context(|| format!("could not open progress log '{}'", file))?;
+        context.progress_log = Some(log);
+    }
+    if let Some(file) = m.get_one::<String>("Log") {
+        let log = DeletionLog::open(Path::new(file)).with_context(|| format!("could not open deletion log '{}'", file))?;
+        context.deletion_log = Some(log);
+    }
+    if let Some(file) = m.get_one::<String>("SkippedReport") {
+        let report = SkipReport::open(Path::new(file)).with_context(|| format!("could not create skipped files report '{}'", file))?;
+        context.skip_report = Some(report);
+    }
 
-    // Generate hashes
-    println!("Hashing images...");
+    // Explore the filetree for images, by default the current directory's. --across scans its
+    // directories instead. A --reference library is scanned too, as the last root.
+    let mut roots: Vec<PathBuf> = match (m.get_many::<String>("Across"), m.get_many::<String>("Dir")) {
+        (Some(dirs), _) => dirs.map(|dir| extended_path(Path::new(dir))).collect(),
+        (None, Some(dirs)) => scan_roots(&dirs.map(String::as_str).collect::<Vec<_>>(), context)?,
+        (None, None) => vec![extended_path(Path::new("."))],
+    };
+    let reference_root = match m.get_one::<String>("Reference") {
+        Some(dir) => {
+            let library = scan_root(dir).context("could not use the --reference library")?;
+            // An image in both would be its own duplicate, and which of the two it counts under is arbitrary
+            if let Some(root) = roots.iter().filter_map(|root| fs::canonicalize(root).ok())
+                .find(|root| root.starts_with(&library) || library.starts_with(root))
+            {
+                anyhow::bail!("--reference '{}' and the scanned directory '{}' overlap", dir, root.display());
+            }
+            roots.push(library);
+            Some(roots.len() - 1)
+        },
+        None => None,
+    };
+    let roots = roots;
+    let across = m.contains_id("Across");
+    let remove_from = match m.get_one::<String>("RemoveFrom") {
+        Some(dir) => {
+            let dir = extended_path(Path::new(dir));
+            Some(roots.iter().position(|root| *root == dir)
+                .with_context(|| format!("--remove-from '{}' must be one of the --across directories", dir.display()))?)
+        },
+        None => None,
+    };
+    let outputs: Vec<(&str, &str)> = [("--keep", "Keep"), ("--move-to", "MoveTo"), ("--quarantine", "Quarantine"), ("--review-dir", "ReviewDir")].into_iter()
+        .filter_map(|(option, id)| Some((option, m.get_one::<String>(id)?.as_str())))
+        .collect();
+    let scan_options = ScanOptions {
+        all_formats: m.get_flag("AllFormats"),
+        archives: cfg!(feature = "zip") && m.get_flag("DedupWithinArchives"),
+        excluded: excluded_output_dirs(&outputs, &roots, m.get_flag("ScanOutputs"))?,
+        skip_marked: !m.get_flag("ScanOutputs"),
+        exclude: m.get_many::<PathGlob>("Exclude").map_or(vec![], |globs| globs.cloned().collect()),
+        include: m.get_many::<PathGlob>("Include").map_or(vec![], |globs| globs.cloned().collect()),
+        max_depth: m.get_one::<usize>("MaxDepth").copied(),
+        follow_symlinks: m.get_flag("FollowSymlinks"),
+        respect_ignore: m.get_flag("RespectIgnore"),
+    };
 
-    let thread_count: usize;
+    let hash_options = HashOptions {
+        trim_borders: m.get_flag("TrimBorders"),
+        exif_rotate: !m.get_flag("NoExifRotate"),
+    };
+    let (terminal, events);
+    let progress: &dyn Progress = if json_events() {
+        events = EventProgress { phase: Mutex::new("starting"), hashing: Mutex::new(HashingTally::default()), dry_run: m.get_flag("DryRun") };
+        &events
+    } else {
+        terminal = TerminalProgress::new()?;
+        &terminal
+    };
+    // Under --dry-run every change goes through a stand-in that only records it
+    let dry_run = m.get_flag("DryRun").then(DryRun::default);
+    let ops: &dyn FileOps = match &dry_run {
+        Some(dry_run) => dry_run,
+        None => &RealFs,
+    };
 
-    if let Some(&t) = m.get_one::<usize>("Threads") {
-        let max_threads = available_parallelism().unwrap();
-        thread_count = std::cmp::min(t, max_threads.into());
+    // A checkpoint left by an interrupted run replaces the scan with the files it found
+    let checkpoint_file = m.get_one::<String>("Resume").map(PathBuf::from);
+    let resumed = match &checkpoint_file {
+        Some(file) if file.exists() => Some(Checkpoint::read(file, &hash_options)
+            .with_context(|| format!("could not resume from checkpoint '{}'", file.display()))?),
+        _ => None,
+    };
+    let resuming = resumed.is_some();
+    let (mut paths, mut resumed_hashes) = if let Some(resumed) = resumed {
+        let resumed = resumed.still_present(progress);
+        (resumed.files, resumed.hashes)
+    } else if m.contains_id("FromFile") || m.get_flag("FromStdin") {
+        // A list from find and the like is taken as it is, without a walk or the walk's filters
+        let images = match m.get_one::<String>("FromFile") {
+            Some(file) => {
+                let list = fs::File::open(file).with_context(|| format!("could not open the file list '{}'", file))?;
+                get_images_in_list(io::BufReader::new(list), scan_options.all_formats, context, progress)
+                    .with_context(|| format!("could not read the file list '{}'", file))?
+            },
+            None => get_images_in_list(io::stdin().lock(), scan_options.all_formats, context, progress)
+                .context("could not read the file list from standard input")?,
+        };
+        let total_size: u64 = images.iter().map(|(_, size)| size).sum();
+        status(match images.len() {
+            0 => String::from("Found no image files in the list"),
+            1 => format!("Found 1 image file in the list ({})", HumanBytes(total_size)),
+            count => format!("Found {} image files in the list ({})", HumanCount(count as u64), HumanBytes(total_size)),
+        });
+        (images, HashMap::new())
     } else {
-        thread_count = 4;
+        let spin = spinner();
+        spin.set_message("Looking for image files...");
+        context.progress_start("scanning", 0, progress);
+        spin.enable_steady_tick(Duration::from_millis(50));
+        let mut images = vec![];
+        for root in &roots {
+            match get_images_in_dir(root, &scan_options, context, progress) {
+                Ok(found) => images.extend(found),
+                Err(why) if roots.len() > 1 => {
+                    progress.on_warning(&format!("could not scan '{}' for images, going on without it: {}", root.display(), why));
+                    context.file_failed(format!("could not scan '{}': {}", root.display(), why));
+                    context.record_skip(SkipReason::UnreadableDir, root, why, progress);
+                },
+                Err(why) => return Err(why).with_context(|| format!("could not scan '{}' for images", root.display())),
+            }
+        }
+        let images = filter_by_age(
+            images,
+            m.get_one::<SystemTime>("OlderThan").copied(),
+            m.get_one::<SystemTime>("NewerThan").copied(),
+            context,
+            progress,
+        );
+        let min_size = m.get_one::<u64>("MinSize").copied().unwrap_or(0);
+        let (images, small) = filter_by_size(images, min_size, context, progress);
+        if small > 0 {
+            progress.on_message(&format!("Left out {} images smaller than --min-size {}", small, HumanBytes(min_size)));
+        }
+        let (images, linked) = collapse_hard_links(images, context, progress);
+        context.progress_finish(images.len() as u64);
+        let total_size = get_total_size_of_files(&images).context("could not read the size of the image files")?;
+        spin.finish_with_message(match images.len() {
+            0 => String::from("Found no image files"),
+            1 => format!("Found 1 image file ({})", HumanBytes(total_size)),
+            count => format!("Found {} image files ({})", HumanCount(count as u64), HumanBytes(total_size)),
+        });
+        if linked > 0 {
+            status(format!("Ignored {} hard links or symlinks to images that were already found", linked));
+        }
+        let mut paths = vec![];
+        for im in &images {
+            let path = im.path();
+            let Some(utf8_path) = path.to_str() else {
+                eprintln!("Warning: skipping '{}', it is not a valid UTF-8 path", path.display());
+                context.record_skip(SkipReason::NonUtf8Path, &path, "", progress);
+                continue;
+            };
+            if scan_options.archives && is_archive(&path) {
+                match list_archive(utf8_path, scan_options.all_formats) {
+                    Ok(members) => paths.extend(members),
+                    Err(why) => {
+                        eprintln!("Warning: skipping archive '{}', could not read it: {}", utf8_path, why);
+                        context.file_failed(format!("could not read archive '{}': {}", utf8_path, why));
+                        context.record_skip(SkipReason::UnreadableArchive, &path, why, progress);
+                    },
+                }
+                continue;
+            }
+            // The size of the image itself, not of a symlink to it
+            let size = fs::metadata(im.path()).map(|meta| meta.len()).unwrap_or(0);
+            paths.push((String::from(utf8_path), size));
+        }
+        (paths, HashMap::new())
+    };
+    if json_events() {
+        let bytes: u64 = paths.iter().map(|(_, size)| size).sum();
+        emit_event("scan_complete", &format!(r#""files":{},"bytes":{}"#, paths.len(), bytes));
     }
+    require_images(&paths, &roots, m.contains_id("FromFile") || m.get_flag("FromStdin"))?;
+
+    // Generate hashes
+    status("Hashing images...");
+
+    // More threads than CPUs only adds contention, so requests are clamped, and 0 (the default) means "one per CPU"
+    let cpus = available_parallelism().map_or(1, |cpus| cpus.get());
+    let thread_count = match m.get_one::<usize>("HashThreads").or(m.get_one::<usize>("Threads")).copied().unwrap_or(0) {
+        0 => cpus,
+        t => std::cmp::min(t, cpus),
+    };
+    // The hashing threads times the decoder threads of each stays within the CPUs, unless asked otherwise
+    let decoder_threads = m.get_one::<usize>("DecoderThreads").copied().unwrap_or(std::cmp::max(1, cpus / thread_count));
+    context.decoder_threads = decoder_threads;
     
-    let mut paths = vec![];
-    for im in &images {
-        paths.push(String::from(im.path().to_str().unwrap()));
+    // read_dir order differs between platforms and runs, so images are hashed in path order unless
+    // --no-sort asks for the order they were found in
+    if !m.get_flag("NoSort") {
+        paths.sort_unstable_by(|a, b| a.0.cmp(&b.0));
     }
-    let hashes = generate_hashes_multithreaded(paths, sty, thread_count).unwrap();
-    let mut keys = vec![];
+    context.summary.scanned.store(paths.len() as u64, Ordering::Relaxed);
+    check_aborted(context)?;
 
-    for hash in hashes {
-        keys.push((hash.1.to_u64(), hash.0));
+    // Images the interrupted run already hashed are taken as they are, new hashes are recorded as they're made
+    let mut resumed = vec![];
+    if let Some(file) = &checkpoint_file {
+        let checkpoint = if resuming {Checkpoint::append(file)} else {Checkpoint::create(file, &hash_options, &paths)}
+            .with_context(|| format!("could not write checkpoint '{}'", file.display()))?;
+        context.checkpoint = Some(checkpoint);
+        if resuming {
+            let mut unhashed = vec![];
+            for (path, size) in paths {
+                match resumed_hashes.remove(&path) {
+                    Some((_, hash, dimensions)) => resumed.push((path, hash, dimensions)),
+                    None => unhashed.push((path, size)),
+                }
+            }
+            paths = unhashed;
+            status(format!("Resuming from '{}', {} images were hashed already, {} left to hash", file.display(), resumed.len(), paths.len()));
+        }
     }
+    if m.get_flag("RobustMatch") {
+        context.centre_hashes = Some(Mutex::new(HashMap::new()));
+    }
+    let consensus = m.get_one::<u32>("Consensus").copied();
+    if consensus.is_some() {
+        context.average_hashes = Some(Mutex::new(HashMap::new()));
+    }
+    let hash_bits = m.get_one::<u32>("HashBits").copied().unwrap_or(64);
+    if hash_bits > 64 {
+        context.wide_hashes = Some((hash_bits.isqrt(), Mutex::new(HashMap::new())));
+    }
+    // The reference of --like is hashed first, so a file that can't be decoded fails before the long part
+    let like = match m.get_one::<String>("Like") {
+        Some(file) => {
+            let (hash, _) = hash_image(file, hash_options, context, progress).with_context(|| format!("could not hash '{}'", file))?;
+            Some((file, hash.to_u64()))
+        },
+        None => None,
+    };
 
-    // find duplicate images
-    let spin = ProgressBar::new_spinner();
-    spin.set_message("Finding dupicates...");
-    spin.enable_steady_tick(Duration::from_millis(50));
+    // Reuse hashes from the cache where the file hasn't changed since it was stored
+    let cache_file = m.get_one::<String>("Cache").filter(|_| !m.get_flag("NoCache")).map(PathBuf::from);
+    let _cache_lock = match &cache_file {
+        Some(file) => Some(HashCache::lock(file).with_context(|| format!("could not lock cache '{}'", file.display()))?),
+        None => None,
+    };
+    let mut cache = match &cache_file {
+        Some(file) => match HashCache::load(file, &hash_options, progress) {
+            Ok(cache) => Some(cache),
+            Err(why) => {
+                eprintln!("Warning: could not read cache '{}', starting afresh: {}", file.display(), why);
+                Some(HashCache::new(&hash_options))
+            },
+        },
+        None => None,
+    };
 
-    let (orig, dups) = find_duplicates(keys);
+    let mut cached = vec![];
+    if let Some(cache) = &mut cache {
+        let mut uncached = vec![];
+        for (path, size) in paths {
+            match cache.get(&path) {
+                Some((hash, dimensions)) => cached.push((path, hash, dimensions)),
+                None => uncached.push((path, size)),
+            }
+        }
+        paths = uncached;
+        status(format!("Found {} cached hashes, {} images left to hash", cached.len(), paths.len()));
 
-    spin.finish_with_message(format!("Found {} original images and {} duplicates.", orig.len(), dups.len()));
+        if let Some(&sample_size) = m.get_one::<usize>("VerifyCache") {
+            let sampled = std::cmp::min(sample_size, cached.len());
+            let stale = verify_cached_hashes(&mut cached, cache, sample_size, context, progress, hash_options);
+            status(format!("Verified {} cached hashes, {} were stale", sampled, stale));
+        }
+    }
+    // Caches and checkpoints only hold dHashes, so their images are decoded again for any extra hashes
+    if context.needs_extra_hashes() {
+        let stored: Vec<&String> = cached.iter().chain(&resumed).map(|(path, _, _)| path).collect();
+        stored.par_iter().for_each(|path| {
+            if let Ok(image) = open_oriented(path, hash_options, context, progress) {
+                let image = if hash_options.trim_borders {trim_borders(&image)} else {image};
+                context.record_extra_hashes(path, &image);
+            }
+        });
+    }
 
-    // Do copying or deleting
-    let spin = ProgressBar::new_spinner();
+    if m.get_flag("Stream") {
+        context.match_stream = Some(Mutex::new(HashMap::new()));
+        for (path, hash, _) in &cached {
+            if let Some(first) = context.stream_match(path, hash.to_u64()) {
+                println!("Likely duplicate: '{}' matches '{}'", path, first);
+            }
+        }
+    }
 
-    if let Some(path) = m.get_one::<String>("Keep") {  // user wants to keep images
-        spin.set_message(format!("Copying original images into '{}'", path));
-        spin.enable_steady_tick(Duration::from_millis(50));
+    // Exact copies are matched by their bytes and take the hash of the copy that is decoded
+    let (paths, exact_copies) = split_exact_copies(paths);
+    let decoded_bytes: u64 = paths.iter().map(|(_, size)| size).sum();
+    // Only the files decoded in place of exact copies are looked up again, the rest aren't copied for it
+    let firsts: HashSet<&String> = exact_copies.iter().map(|(_, first)| first).collect();
+    let first_sizes: HashMap<String, u64> = paths.iter().filter(|(path, _)| firsts.contains(path)).cloned().collect();
+    let hash_start = Instant::now();
 
-        if let Err(why) = fs::create_dir(path) {
-            spin.finish_with_message(format!("Could not create directory {}: {}", path, why))
-        }
+    context.progress_start("hashing", paths.len() as u64, progress);
+    // The bars' ETAs take over from the estimate once hashing is under way
+    let mut paths = paths;
+    let mut sampled = vec![];
+    // Sampling the estimate costs time, which is wasted when nobody sees it
+    if paths.len() >= ESTIMATE_MIN_IMAGES && !QUIET.load(Ordering::Relaxed) {
+        let (image_count, parallel) = (paths.len(), std::cmp::min(hashing_threads(paths.len(), thread_count), cpus));
+        let estimate;
+        (sampled, estimate) = estimate_hashing(&mut paths, parallel, context, progress, hash_options).context("could not hash the images")?;
+        status(format!(
+            "About to hash {} images, {} to decode, {} estimated",
+            image_count, HumanBytes(decoded_bytes), rough_duration(estimate)
+        ));
+    }
+    let mut hashes = if let Some(&readers) = m.get_one::<usize>("ReadThreads") {
+        let hashers = hashing_threads(paths.len(), thread_count);
+        generate_hashes_pipelined(paths, readers, hashers, context, progress, hash_options)
+    } else if hashing_threads(paths.len(), thread_count) == 1 {
+        with_decoder_pool(context.decoder_threads, || generate_hashes(paths, 0, 1, context, progress, hash_options))
+    } else if m.get_flag("PerRootThreads") {
+        hash_per_root(split_per_root(paths, &roots, thread_count), context, progress, hash_options)
+    } else {
+        generate_hashes_multithreaded(paths, thread_count, context, progress, hash_options)
+    }.context("could not hash the images")?;
+    hashes.extend(sampled);
+    hashes.extend(resumed);
 
-        match copy_files_to_dir(orig, Path::new(path)) {
-            Ok(_) => spin.finish_with_message(format!("Copied original images into '{}'", path)),
-            Err(why) => spin.finish_with_message(format!("Failed to copy images: {}", why))
+    if !exact_copies.is_empty() {
+        let hashed: HashMap<String, (Dhash, Dimensions)> = hashes.iter()
+            .filter(|(path, _, _)| firsts.contains(path))
+            .map(|(path, hash, dimensions)| (path.clone(), (*hash, *dimensions)))
+            .collect();
+        let mut copy_bytes = 0;
+        for (path, first) in exact_copies {
+            match hashed.get(&first) {
+                Some(&(hash, dimensions)) => {
+                    copy_bytes += first_sizes.get(&first).copied().unwrap_or(0);
+                    if let Some(first) = context.stream_match(&path, hash.to_u64()) {
+                        println!("Likely duplicate: '{}' matches '{}'", path, first);
+                    }
+                    context.copy_extra_hashes(&first, &path);
+                    hashes.push((path, hash, dimensions));
+                },
+                None if context.out_of_time() => context.defer(),
+                None => {
+                    context.record_skip(SkipReason::DecodeFailed, Path::new(&path), format!("identical to '{}'", first), progress);
+                    let mut damaged = context.damaged.lock().unwrap();
+                    if damaged.contains(&first) {damaged.push(path);}
+                },
+            }
         }
+        // Estimated from how fast the decoded images went
+        let secs_per_byte = hash_start.elapsed().as_secs_f64() / decoded_bytes.max(1) as f64;
+        status(format!(
+            "Skipped decoding {} of exact copies, saving about {:.1}s",
+            HumanBytes(copy_bytes), secs_per_byte * copy_bytes as f64
+        ));
+    }
+    check_aborted(context)?;
+    if let Some(checkpoint) = &context.checkpoint {
+        checkpoint.finish_hashing(context.deferred.load(Ordering::Relaxed) > 0);
+    }
+    if cache.is_some() {
+        report_deferred(context, "images were left unhashed, the next run with the same cache picks up where this one stopped");
     } else {
-        spin.set_message("Deleting duplicate images...");
-        spin.enable_steady_tick(Duration::from_millis(50));
+        report_deferred(context, "images were left unhashed, use --cache so the next run can pick up where this one stopped");
+    }
 
-        match delete_files(dups) {
-            Ok(_) => spin.finish_with_message("Deleted duplicate images"),
-            Err(why) => spin.finish_with_message(format!("Failed to delete duplicate images: {}", why))
+    if let (Some(cache), Some(file)) = (&mut cache, &cache_file) {
+        for (path, hash, dimensions) in &hashes {
+            cache.insert(path, *hash, *dimensions);
+        }
+        let stale = cache.prune();
+        if stale > 0 && context.verbose {
+            println!("Dropped {} cache entries of files that were deleted or changed", stale);
+        }
+        if let Err(why) = cache.save(file) {
+            eprintln!("Warning: could not write cache '{}': {}", file.display(), why);
         }
     }
-}
+    hashes.extend(cached);
+    // Freshly hashed, sampled, resumed, copied and cached images each come in a batch of their own, and
+    // with --threshold the order decides which image starts a group, so they are put back in path order
+    // to give the same groups whatever was cached or how many threads hashed
+    if !m.get_flag("NoSort") {
+        hashes.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    }
 
-fn cli() -> Command {
-    Command::new("FastDedup")
-        .arg(
-            Arg::new("Keep")
-            .short('k')
-            .long("keep")
+    let mut damaged = std::mem::take(&mut *context.damaged.lock().unwrap());
+    damaged.sort_unstable();  // the hashing threads add to it in whatever order they finish
+    if !damaged.is_empty() {
+        status(format!("Found {} truncated or undecodable images, they were left out", damaged.len()));
+        if let Some(dir) = m.get_one::<String>("Quarantine") {
+            let moved = quarantine(ops, damaged, Path::new(dir), context, progress)
+                .with_context(|| format!("could not create quarantine directory '{}'", dir))?;
+            if dry_run.is_none() {
+                mark_output_dir(Path::new(dir), progress);
+                if moved > 0 {status(format!("Moved {} damaged images into '{}'", moved, dir));}
+            }
+        }
+    }
+    let mut keys = Vec::with_capacity(hashes.len());
+
+    // With --one-per-dir the parent folder is part of the key, so groups never span folders
+    let one_per_dir = m.get_flag("OnePerDir");
+    let prefer_metadata = m.get_flag("PreferMetadata");
+    let keep_age = keep_age(&m);
+    for (path, hash, dimensions) in hashes {
+        let folder = if one_per_dir {
+            Path::new(&path).parent().map(|parent| parent.to_path_buf())
+        } else {
+            None
+        };
+        // Only images that both decoded and can still be read take part, so every group member has real
+        // dimensions (from the decode, never a separate header read) and a real size to be compared on
+        let size = match image_size(&path) {
+            Ok(size) => size,
+            Err(why) => {
+                eprintln!("Warning: skipping '{}', could not read its size after hashing: {}", path, why);
+                context.file_failed(format!("could not read the size of '{}': {}", path, why));
+                context.record_skip(SkipReason::NoMetadata, Path::new(&path), why, progress);
+                continue;
+            },
+        };
+        let root = root_index(Path::new(&path), &roots).unwrap_or(0);
+        let jpeg_quality = estimate_jpeg_quality(&path);
+        let metadata = if prefer_metadata {metadata_richness(&path)} else {0};
+        let modified = keep_age.and_then(|_| fs::metadata(&path).and_then(|meta| meta.modified()).ok());
+        keys.push(((hash.to_u64(), folder), ImageInfo { path, hash: hash.to_u64(), dimensions, size, root, jpeg_quality, metadata, modified }));
+    }
+    check_aborted(context)?;
+
+    // With --robust-match images whose full hashes or centre hashes agree share a key, linked in chains
+    // so a repost can join its group through either hash
+    if let Some(centres) = &context.centre_hashes {
+        let centres = centres.lock().unwrap();
+        let mut parents: Vec<usize> = (0..keys.len()).collect();
+        let mut first_full: HashMap<u64, usize> = HashMap::new();
+        let mut first_centre: HashMap<u64, usize> = HashMap::new();
+        for (i, (_, info)) in keys.iter().enumerate() {
+            let firsts = [
+                Some(*first_full.entry(info.hash).or_insert(i)),
+                centres.get(&info.path).map(|&centre| *first_centre.entry(centre).or_insert(i)),
+            ];
+            for first in firsts.into_iter().flatten() {
+                let (a, b) = (find_root(&mut parents, i), find_root(&mut parents, first));
+                parents[a.max(b)] = a.min(b);
+            }
+        }
+        for i in 0..keys.len() {
+            let root = find_root(&mut parents, i);
+            keys[i].0.0 = keys[root].1.hash;
+        }
+    }
+
+    // Clustering is only for exploring, it never deletes or copies anything
+    if let Some((file, reference)) = like {
+        let distance = m.get_one::<u32>("LikeDistance").copied().unwrap_or(DEFAULT_LIKE_DISTANCE);
+        let image_count = keys.len();
+        print_similar(file, reference, keys.into_iter().map(|(_, info)| info).collect(), distance, image_count);
+        return Ok(());
+    }
+    if let Some(&distance) = m.get_one::<u32>("Cluster") {
+        let image_count = keys.len();
+        let index = m.get_one::<HashIndex>("Index").copied();
+        if index == Some(HashIndex::Map) && distance > 0 {
+            anyhow::bail!("--index map only finds identical hashes, use bktree or lsh for --cluster {}", distance);
+        }
+        let clusters = cluster_images(keys.into_iter().map(|(_, info)| info).collect(), distance, index);
+        print_clusters(&clusters, image_count, distance);
+        return Ok(());
+    }
+
+    // Library images go first, so each starts the group that scanned images matching it join, rather than
+    // a scanned image starting one that its look-alikes, which can't match it, then can't join
+    if let Some(library) = reference_root {
+        keys.sort_by_key(|(_, info)| info.root != library);
+    }
+
+    // find duplicate images
+    let rules = QualityRules {
+        prefer: preference,
+        preferred_name: m.get_one::<Regex>("PreferName").cloned(),
+        remove_from,
+        reference: reference_root,
+        keep_age,
+    };
+    let max_aspect_diff = m.get_one::<f64>("MaxAspectDiff").copied();
+    let same_dimensions = m.get_flag("RequireSameDimensions");
+    context.progress_start("grouping", keys.len() as u64, progress);
+    let key_count = keys.len() as u64;
+    // Only images whose hashes already matched get decoded again for --pixel-threshold, and each image's
+    // thumbnail is made once however many candidates it is compared against
+    let pixel_threshold = m.get_one::<u8>("PixelThreshold").copied();
+    let thumbnails: Mutex<HashMap<String, Option<image::RgbImage>>> = Mutex::new(HashMap::new());
+    let same_pixels = |a: &ImageInfo, b: &ImageInfo, threshold: u8| {
+        let mut thumbnails = thumbnails.lock().unwrap();
+        for info in [a, b] {
+            if !thumbnails.contains_key(&info.path) {
+                thumbnails.insert(info.path.clone(), thumbnail(&info.path, hash_options, context, progress));
+            }
+        }
+        match (&thumbnails[&a.path], &thumbnails[&b.path]) {
+            (Some(a), Some(b)) => mean_pixel_difference(a, b) <= threshold as f64,
+            _ => false,  // a match that can't be confirmed is not a match
+        }
+    };
+    // Under --consensus the average hashes have to agree as well, an image without one matches nothing
+    let average_hashes = context.average_hashes.as_ref().map(|hashes| hashes.lock().unwrap());
+    let averages_agree = |a: &ImageInfo, b: &ImageInfo, max: u32| {
+        let average = |info: &ImageInfo| average_hashes.as_ref()?.get(&info.path).copied();
+        average(a).zip(average(b)).is_some_and(|(a, b)| (a ^ b).count_ones() <= max)
+    };
+    // Under --hash-bits the wide hashes have to be as close, for their size, as --threshold allows the dHashes
+    let wide_hashes = context.wide_hashes.as_ref().map(|(_, hashes)| hashes.lock().unwrap());
+    let wide_threshold = threshold * hash_bits / 64;
+    let wide_agree = |a: &ImageInfo, b: &ImageInfo| {
+        let wide = |info: &ImageInfo| wide_hashes.as_ref()?.get(&info.path);
+        wide(a).zip(wide(b)).is_some_and(|(a, b)| a.distance(b) <= wide_threshold)
+    };
+    let groups = find_duplicates(keys, threshold, &rules, |a: &ImageInfo, b: &ImageInfo| {
+        (!across || a.root != b.root)
+            // Only matches against the library count, images that are only alike each other are left alone
+            && reference_root.is_none_or(|library| (a.root == library) != (b.root == library))
+            && consensus.is_none_or(|max| averages_agree(a, b, max))
+            && (wide_hashes.is_none() || wide_agree(a, b))
+            && max_aspect_diff.is_none_or(|max| (a.aspect_ratio() - b.aspect_ratio()).abs() <= max)
+            && (!same_dimensions || a.dimensions == b.dimensions)
+            && pixel_threshold.is_none_or(|threshold| same_pixels(a, b, threshold))
+    }, progress);
+    // The rest of the work, up to the summary, runs under a spinner
+    let spin = spinner();
+    spin.set_message("Finding duplicates...");
+    spin.enable_steady_tick(Duration::from_millis(50));
+    let mut groups = groups;
+    if rules.prefer == Prefer::Ssim {
+        spin.set_message("Comparing the members of each group...");
+        let limit = m.get_one::<usize>("MaxGroupSize").copied().unwrap_or(SSIM_MAX_GROUP);
+        let (undecoded, oversized) = groups.par_iter_mut()
+            .filter(|group| !group.duplicates.is_empty())
+            .map(|group| match group.len() > limit {
+                true => (0, 1),
+                false => (!elect_by_ssim(group, &rules, context, progress, hash_options) as usize, 0),
+            })
+            .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+        if oversized > 0 {
+            spin.suspend(|| status(format!("{} groups have more than {} members, their original was chosen by resolution", oversized, limit)));
+        }
+        if undecoded > 0 {
+            spin.suspend(|| status(format!("{} groups had a member that could not be decoded again, their original was chosen by resolution", undecoded)));
+        }
+    }
+    for group in groups.iter().filter(|group| !group.duplicates.is_empty()) {
+        progress.on_group_found(group);
+    }
+
+    context.progress_finish(key_count);
+
+    // Read the baseline before saving, in case both options point at the same file
+    let baseline = match m.get_one::<String>("Baseline") {
+        Some(file) => Some(load_snapshot(Path::new(file)).with_context(|| format!("could not read baseline '{}'", file))?),
+        None => None,
+    };
+    let previous_actions = match m.get_one::<String>("DiffActions") {
+        Some(file) => Some(load_snapshot(Path::new(file)).with_context(|| format!("could not read snapshot '{}'", file))?),
+        None => None,
+    };
+
+    if let Some(file) = m.get_one::<String>("SaveGroups") {
+        save_snapshot(&groups, Path::new(file)).with_context(|| format!("could not write snapshot '{}'", file))?;
+    }
+
+    // Comparing against a baseline only reports, it never deletes or copies anything
+    if let Some(baseline) = baseline {
+        spin.finish_and_clear();
+        report_new_duplicates(&groups, &baseline);
+        return Ok(());
+    }
+    if let Some(previous) = previous_actions {
+        spin.finish_and_clear();
+        report_action_changes(&groups, &previous);
+        return Ok(());
+    }
+    // Unique paths are printed one per line with the count on stderr, so the list can be piped on
+    if m.get_flag("ListUnique") {
+        spin.finish_and_clear();
+        let unique: Vec<&ImageInfo> = groups.iter().filter(|group| group.len() == 1).map(|group| &group.original).collect();
+        for info in &unique {
+            println!("{}", info.path);
+        }
+        eprintln!("{} of {} images have no duplicate", unique.len(), key_count);
+        return Ok(());
+    }
+    if let Some(dir) = m.get_one::<String>("ReviewDir") {
+        spin.finish_and_clear();
+        let (written, copied) = write_review_dir(&groups, Path::new(dir), progress)
+            .with_context(|| format!("could not write the review directory '{}'", dir))?;
+        println!(
+            "Wrote {} duplicate groups to '{}' as folders of {}. Nothing else has been changed, delete the folder when you are done.",
+            written, dir, if copied {"copies, symlinks could not be created"} else {"symlinks"}
+        );
+        return Ok(());
+    }
+
+    // Groups below the minimum or above the maximum size are left untouched: every member is treated as an original
+    let min_group_size = m.get_one::<usize>("MinGroupSize").copied().unwrap_or(2);
+    let max_group_size = m.get_one::<usize>("MaxGroupSize").copied().unwrap_or(usize::MAX);
+    let mut orig = vec![];
+    let mut dups = vec![];
+    let mut ignored = 0;
+    let mut capped = 0;
+    // Duplicates too small to be worth deleting are still listed, but never deleted
+    let min_savings = m.get_one::<u64>("MinSavings").copied().unwrap_or(0);
+    let mut small = 0;
+    let verbose = m.get_flag("Verbose");
+    let action = chosen_action(&m);
+    let deleting = matches!(action, Action::Delete | Action::MoveTo(_));
+    let mut lost = vec![];
+    // Anything beyond the threshold (joined by --robust-match) is borderline whatever the margin
+    let borderline_above = m.get_one::<u32>("DedupReportThreshold").map(|&margin| threshold.saturating_sub(margin));
+    let confident_only = m.get_flag("ConfidentOnly");
+    let mut borderline = 0;
+    let confirm_exact = m.get_flag("ConfirmExact");
+    let mut inexact = 0;
+    // Under --hardlink each duplicate is paired with the original it is replaced by a link to
+    let hardlinking = action == Action::Hardlink;
+    let mut links = vec![];
+    let listing = action == Action::List;
+
+    let in_range = |group: &DuplicateGroup<ImageInfo>| (min_group_size..=max_group_size).contains(&group.len());
+    let groups = if m.get_flag("Interactive") {
+        if listing {anyhow::bail!("--interactive needs an action to confirm, add --delete, --hardlink, --keep or --move-to");}
+        spin.finish_and_clear();
+        confirm_groups(groups, action.prompt(), in_range).context("could not read the answers")?
+    } else if m.get_flag("Review") {
+        if listing {anyhow::bail!("--review needs an action to review, add --delete, --hardlink, --keep or --move-to");}
+        spin.finish_and_clear();
+        match review_groups(groups, action.prompt(), in_range).context("could not run the review")? {
+            Some(reviewed) => reviewed,
+            None => {
+                println!("Review cancelled, nothing has been changed.");
+                return Ok(());
+            },
+        }
+    } else {
+        groups
+    };
+
+    let keep_best = m.get_one::<usize>("KeepBest").copied().unwrap_or(1);
+    let mut groups = groups;
+    if keep_best > 1 {
+        for group in &mut groups {
+            group.keep_best(keep_best, &rules);
+        }
+    }
+    // The closest matches come first and the doubtful ones last, just above the summary
+    if m.get_flag("SortByDistance") {
+        for group in &mut groups {
+            group.sort_by_distance();
+        }
+        groups.sort_by_key(DuplicateGroup::max_distance);
+    }
+    // Each group is written out as soon as it is settled, so a consumer can start on it straight away
+    let mut jsonl: Option<Box<dyn Write>> = match m.get_one::<String>("Jsonl").map(String::as_str) {
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(file) => Some(Box::new(BufWriter::new(
+            fs::File::create(file).with_context(|| format!("could not create '{}'", file))?
+        ))),
+        None => None,
+    };
+    let mut report = m.get_one::<String>("Report").map(|_| vec![]);
+    for mut group in groups {
+        let oversized = group.len() > max_group_size;
+        if let Some(writer) = jsonl.as_mut().filter(|_| !group.duplicates.is_empty()) {
+            let acted_on = group.len() >= min_group_size && !oversized;
+            writeln!(writer, "{}", group_json(&group, acted_on)).and_then(|_| writer.flush())
+                .context("could not write the --jsonl output")?;
+        }
+        if let Some(report) = report.as_mut().filter(|_| !group.duplicates.is_empty()) {
+            report.push(group_json(&group, group.len() >= min_group_size && !oversized));
+        }
+        if group.duplicates.is_empty() || (group.len() >= min_group_size && !oversized) {
+            if json_events() && !group.duplicates.is_empty() {
+                let json = group_json(&group, true);
+                emit_event("group", &json[1..json.len() - 1]);
+            } else if (verbose || listing) && !group.duplicates.is_empty() {
+                print_group(&group, false, borderline_above);
+            }
+            for dup in &group.duplicates {
+                if is_suspicious_discard(&group.original, dup) {
+                    eprintln!(
+                        "Warning: keeping '{}' ({}x{}, {}) over '{}' ({}x{}, {}), the discarded copy is much larger on disk and may be higher quality",
+                        group.original.path, group.original.dimensions.0, group.original.dimensions.1, HumanBytes(group.original.size),
+                        dup.path, dup.dimensions.0, dup.dimensions.1, HumanBytes(dup.size),
+                    );
+                }
+            }
+            let (unsure, sure): (Vec<ImageInfo>, Vec<ImageInfo>) = std::mem::take(&mut group.duplicates).into_iter()
+                .partition(|dup| is_borderline(&group, dup, borderline_above));
+            borderline += unsure.len();
+            // Borderline matches may well be different images, so --confident-only treats them as originals
+            let candidates: Vec<ImageInfo> = if confident_only {
+                orig.extend(unsure);
+                sure
+            } else {
+                sure.into_iter().chain(unsure).collect()
+            };
+            // Under --confirm-exact only byte for byte copies of the original go, the rest are kept like originals
+            let candidates = if confirm_exact {
+                let original = Path::new(&group.original.path);
+                let (identical, different): (Vec<ImageInfo>, Vec<ImageInfo>) = candidates.into_par_iter()
+                    .partition(|dup| files_identical(original, Path::new(&dup.path)).unwrap_or(false));
+                inexact += different.len();
+                orig.extend(different);
+                identical
+            } else {
+                candidates
+            };
+            let (doomed, spared): (Vec<ImageInfo>, Vec<ImageInfo>) = candidates.into_iter().partition(|dup| dup.size >= min_savings);
+            if deleting {
+                if let Some(why) = loses_every_copy(&group.original, &group.also_kept, &doomed) {
+                    lost.push(why);
+                }
+            }
+            small += spared.len();
+            if let Some(log) = &context.deletion_log {
+                log.add_originals(doomed.iter().map(|dup| (dup.path.clone(), group.original.path.clone())));
+            }
+            if hardlinking {
+                links.extend(doomed.iter().map(|dup| (group.original.path.clone(), dup.path.clone())));
+            }
+            orig.push(group.original);
+            orig.extend(group.also_kept);
+            dups.extend(doomed.into_iter().map(|dup| dup.path));
+        } else {
+            let reason = if oversized {
+                // Thousands of matches (video frames, blank scans) are far more likely a degenerate hash than real copies
+                eprintln!(
+                    "Warning: leaving the group of {} images around '{}' untouched, it is larger than --max-group-size {}",
+                    group.len(), group.original.path, max_group_size
+                );
+                capped += 1;
+                SkipReason::AboveMaxGroupSize
+            } else {
+                ignored += 1;
+                SkipReason::BelowMinGroupSize
+            };
+            for member in std::iter::once(&group.original).chain(&group.also_kept).chain(&group.duplicates) {
+                context.record_skip(reason, Path::new(&member.path), format!("group of {}", group.len()), progress);
+            }
+            orig.push(group.original);
+            orig.extend(group.also_kept);
+            orig.extend(group.duplicates);
+        }
+    }
+
+    // Written before anything is deleted or copied, so it describes the groups even if the action fails
+    if let (Some(file), Some(report)) = (m.get_one::<String>("Report"), report) {
+        let json = if report.is_empty() {String::from("[]\n")} else {format!("[\n  {}\n]\n", report.join(",\n  "))};
+        fs::write(file, json).with_context(|| format!("could not write the report '{}'", file))?;
+    }
+
+    if !lost.is_empty() {
+        anyhow::bail!(
+            "refusing to delete anything, {} groups would lose every copy of their image:\n{}",
+            lost.len(), lost.join("\n")
+        );
+    }
+
+    let found = dups.len() + small;
+    context.summary.duplicates.store(found as u64, Ordering::Relaxed);
+    let mut untouched = vec![];
+    if ignored > 0 {untouched.push(format!("{} smaller groups", ignored));}
+    if capped > 0 {untouched.push(format!("{} oversized groups", capped));}
+    if small > 0 {untouched.push(format!("{} duplicates below --min-savings", small));}
+    if confident_only && borderline > 0 {untouched.push(format!("{} borderline matches", borderline));}
+    if inexact > 0 {untouched.push(format!("{} duplicates that aren't exact copies", inexact));}
+    if untouched.is_empty() {
+        spin.finish_with_message(format!("Found {} original images and {} duplicates.", orig.len(), found));
+    } else {
+        spin.finish_with_message(format!(
+            "Found {} original images and {} duplicates ({} left untouched).", orig.len(), found, untouched.join(" and ")
+        ));
+    }
+
+    if json_events() {
+        emit_event("duplicates_found", &format!(r#""originals":{},"duplicates":{}"#, orig.len(), found));
+    }
+    if borderline > 0 {
+        status(format!(
+            "{} of the matches are borderline, within {} bits of the match threshold, and are the most likely to be wrong. {}",
+            borderline, m.get_one::<u32>("DedupReportThreshold").unwrap(),
+            if confident_only {"They were left alone, check them by hand."} else {"Check them by hand, or add --confident-only to leave them alone."}
+        ));
+    }
+    if listing {
+        if !dups.is_empty() && !m.get_flag("GroupOnly") {
+            status(format!(
+                "No action was given, so nothing has been changed. Re-run with --delete to remove the {} duplicates listed above, \
+                or with --keep [<DIR>] to copy the {} originals into a new directory.",
+                dups.len(), orig.len()
+            ));
+        }
+        return Ok(());
+    }
+
+    // Archives are read-only: their members are reported like any other image but never deleted or copied
+    let (archived_orig, orig): (Vec<ImageInfo>, Vec<ImageInfo>) = orig.into_iter()
+        .partition(|info| archive_member(&info.path).is_some());
+    let (archived_dups, mut dups): (Vec<String>, Vec<String>) = dups.into_iter()
+        .partition(|path| archive_member(path).is_some());
+    links.retain(|(original, duplicate): &(String, String)| archive_member(original).is_none() && archive_member(duplicate).is_none());
+    if !archived_orig.is_empty() || !archived_dups.is_empty() {
+        status(format!(
+            "Left {} originals and {} duplicates inside zip archives untouched, archives are only reported on",
+            archived_orig.len(), archived_dups.len()
+        ));
+    }
+
+    // Do copying or deleting
+    if let Action::Hardlink = action {
+        context.progress_start("linking", links.len() as u64, progress);
+        if let Err(why) = hardlink_files(ops, links, context, progress) {
+            eprintln!("Failed to replace duplicate images with hard links: {}", why);
+        }
+        report_deferred(context, "duplicates were not replaced with hard links yet");
+    } else if let Action::Keep(path) = action {
+        // An existing directory is added to, so repeated imports build up one deduplicated collection, but
+        // not without saying so. One that can't be created stops the run before anything is copied.
+        if ops.is_dir(Path::new(path)) {
+            let held = fs::read_dir(path).with_context(|| format!("could not read the --keep directory '{}'", path))?
+                .filter_map(Result::ok)
+                .filter(|entry| entry.file_name() != OUTPUT_MARKER)
+                .count();
+            if held > 0 {
+                eprintln!(
+                    "Warning: '{}' already holds {} files or folders, the originals are copied in alongside them \
+                    (originals it already has a copy of are skipped)", path, held
+                );
+            }
+        } else if ops.exists(Path::new(path)) {
+            anyhow::bail!("'{}' exists but is not a directory, originals can't be copied into it", path);
+        } else {
+            ops.create_dir_all(Path::new(path)).with_context(|| format!("could not create directory '{}'", path))?;
+        }
+        if dry_run.is_none() && Path::new(path).is_dir() {mark_output_dir(Path::new(path), progress);}
+
+        let present = hashes_in_dir(Path::new(path), &scan_options, context, progress, hash_options);
+        let (already_present, orig): (Vec<ImageInfo>, Vec<ImageInfo>) = orig.into_iter()
+            .partition(|info| present.contains(&info.hash));
+        if !already_present.is_empty() {
+            status(format!("Skipped {} originals that are already in '{}'", already_present.len(), path));
+        }
+
+        context.progress_start("copying", orig.len() as u64, progress);
+        let layout = match m.get_one::<RenameTemplate>("RenameTemplate") {
+            Some(template) => CopyLayout::Template(template),
+            None if m.get_flag("PreserveStructure") => CopyLayout::Mirror(&roots),
+            None => CopyLayout::Flat,
+        };
+        if let Err(why) = copy_files_to_dir(ops, orig, Path::new(path), layout, context, progress) {
+            // Failed copies are already counted one by one
+            if !matches!(why, DedupError::SomeCopiesFailed { .. }) {
+                context.summary.errors.fetch_add(1, Ordering::Relaxed);
+            }
+            eprintln!("Failed to copy images: {}", why);
+        }
+        report_deferred(context, "originals were not copied yet");
+    } else {
+        if let Action::MoveTo(path) = action {
+            if !ops.is_dir(Path::new(path)) {
+                ops.create_dir_all(Path::new(path)).with_context(|| format!("could not create directory '{}'", path))?;
+            }
+            if dry_run.is_none() {mark_output_dir(Path::new(path), progress);}
+
+            context.progress_start("moving", orig.len() as u64, progress);
+            // A failed move leaves that original where it was, so deleting its duplicates still leaves a copy
+            if let Err(why) = move_files_to_dir(ops, orig.clone(), Path::new(path), context, progress) {
+                if !matches!(why, DedupError::SomeMovesFailed { .. }) {
+                    context.summary.errors.fetch_add(1, Ordering::Relaxed);
+                }
+                eprintln!("Failed to move images: {}", why);
+            }
+            report_deferred(context, "originals were not moved yet");
+        }
+        let paired = companions_of_duplicates(ops, &dups, &orig);
+        if !paired.is_empty() && context.with_companions {
+            status(format!("Also deleting {} companion files of the duplicates", paired.len()));
+            // A companion is logged with the original of the duplicate it belongs to
+            if let Some(log) = &context.deletion_log {
+                let originals: Vec<(String, String)> = paired.iter().filter_map(|companion| {
+                    let stem = Path::new(companion).with_extension("");
+                    let image = dups.iter().find(|dup| Path::new(dup).with_extension("") == stem)?;
+                    Some((companion.clone(), log.original_of(image)?))
+                }).collect();
+                log.add_originals(originals);
+            }
+            dups.extend(paired);
+        } else if !paired.is_empty() {
+            status(format!(
+                "Leaving {} companion files (such as Live Photo videos) of the duplicates in place, add --with-companions to delete them too",
+                paired.len()
+            ));
+        }
+        context.progress_start("deleting", dups.len() as u64, progress);
+        if let Err(why) = delete_files(ops, dups, context, progress) {
+            eprintln!("Failed to delete duplicate images: {}", why);
+        }
+        report_deferred(context, "duplicates were not deleted yet");
+    }
+    if let Some(dry_run) = dry_run {
+        // The file events already said what would happen
+        if !json_events() {
+            for line in dry_run.report() {
+                println!("{}", line);
+            }
+        }
+        context.summary.freed.store(0, Ordering::Relaxed);  // nothing was
+    }
+
+    check_aborted(context)
+}
+
+fn cli() -> Command {
+    let command = Command::new("FastDedup")
+        .arg(
+            Arg::new("Keep")
+            .short('k')
+            .long("keep")
             .default_missing_value("target")
             .num_args(0..=1)
             .help("Keep files and copy originals into new directory (default '/target')")
         )
+        .arg(
+            Arg::new("WithCompanions")
+            .long("with-companions")
+            .action(clap::ArgAction::SetTrue)
+            .help("Delete the companion files (e.g. Live Photo videos) of deleted duplicates, and copy those of originals with --keep")
+        )
+        .arg(
+            Arg::new("PreserveStructure")
+            .long("preserve-structure")
+            .action(clap::ArgAction::SetTrue)
+            .requires("Keep")
+            .help("Recreate each file's folders, relative to the scanned directory, inside the target directory")
+        )
+        .arg(
+            Arg::new("RenameTemplate")
+            .long("rename-template")
+            .value_name("TEMPLATE")
+            .value_parser(parse_rename_template)
+            .requires("Keep")
+            .conflicts_with("PreserveStructure")
+            .help("Name copied originals after a template, e.g. '{date}/{name}_{width}x{height}.{ext}'")
+        )
+        .arg(
+            Arg::new("DryRun")
+            .short('n')
+            .long("dry-run")
+            .action(clap::ArgAction::SetTrue)
+            .help("Go through --delete, --keep, --move-to or --quarantine, but only list what would be deleted, copied or moved")
+        )
+        .arg(
+            Arg::new("Delete")
+            .short('d')
+            .long("delete")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["Keep", "MoveTo"])
+            .help("Delete duplicate images (without --delete, --keep or --move-to, duplicates are only listed)")
+        )
+        .arg(
+            Arg::new("GroupOnly")
+            .long("group-only")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Quarantine", "Interactive", "Review"])
+            .help("Only list the duplicate groups, refusing every option that would change a file")
+        )
+        .arg(
+            Arg::new("Trash")
+            .long("trash")
+            .action(clap::ArgAction::SetTrue)
+            .help("Move duplicates to the trash instead of deleting them, so they can be restored")
+        )
+        .arg(
+            Arg::new("Hardlink")
+            .long("hardlink")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["Delete", "Keep", "MoveTo", "Trash"])
+            .help("Replace each duplicate with a hard link to its original, so every path still works but the bytes are stored once")
+        )
+        .arg(
+            Arg::new("MoveTo")
+            .long("move-to")
+            .value_name("DIR")
+            .conflicts_with("Keep")
+            .help("Move originals into this directory, then delete the duplicates")
+        )
+        .arg(
+            Arg::new("NoCrossDeviceMoves")
+            .long("no-cross-device-moves")
+            .action(clap::ArgAction::SetTrue)
+            .help("Refuse to move files to another file system (by copying and deleting them) with --move-to or --quarantine")
+        )
+        .arg(
+            Arg::new("Interactive")
+            .short('i')
+            .long("interactive")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("Review")
+            .help("Ask before acting on each duplicate group; groups not confirmed are left alone")
+        )
+        .arg(
+            Arg::new("Review")
+            .long("review")
+            .action(clap::ArgAction::SetTrue)
+            .help("Step through the groups in the terminal, choosing what to keep and which groups to act on, before anything is done")
+        )
+        .arg(
+            Arg::new("ReviewDir")
+            .long("review-dir")
+            .value_name("DIR")
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Review", "ListUnique", "Cluster", "LoadHashes", "Baseline", "DiffActions"])
+            .help("Write every duplicate group to DIR as a folder of symlinks to its members, for browsing. Nothing else is changed")
+        )
         .arg(
             Arg::new("Threads")
             .short('t')
             .long("threads")
             .num_args(1)
-            .help("Number of threads to use (default 4)")
+            .help("Number of threads to use, 0 or auto for one per CPU (the default, and the most allowed)")
+            .allow_negative_numbers(true)
+            .value_parser(parse_thread_count)
+        )
+        .arg(
+            Arg::new("ReadThreads")
+            .long("read-threads")
+            .value_name("N")
+            .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+            .conflicts_with("PerRootThreads")
+            .help("Read files on N threads of their own, passing them to the hashing threads to decode, for slow or network storage")
+        )
+        .arg(
+            Arg::new("HashThreads")
+            .long("hash-threads")
+            .value_name("N")
+            .requires("ReadThreads")
+            .conflicts_with("Threads")
+            .allow_negative_numbers(true)
+            .value_parser(parse_thread_count)
+            .help("Number of threads decoding and hashing what --read-threads read, like --threads (the default)")
+        )
+        .arg(
+            Arg::new("DecoderThreads")
+            .long("decoder-threads")
+            .value_name("N")
+            .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+            .help("Threads each hashing thread may use to decode a single JPEG (default: the CPUs divided by --threads, at least 1)")
+        )
+        .arg(
+            Arg::new("AllFormats")
+            .long("all-formats")
+            .action(clap::ArgAction::SetTrue)
+            .help("Consider every format the image decoder supports (PNM, TGA, ICO, HDR, ...), not just JPEG, PNG, WebP, GIF, BMP and TIFF")
+        )
+        .arg(
+            Arg::new("ProgressLog")
+            .long("progress-log")
+            .num_args(1)
+            .value_name("FILE")
+            .help("Write progress events as newline-delimited JSON to FILE (e.g. /dev/fd/3), alongside the progress bars")
+        )
+        .arg(
+            Arg::new("SkippedReport")
+            .long("skipped-report")
+            .value_name("FILE")
+            .help("Write every file that was skipped, and the reason why, to the given file")
+        )
+        .arg(
+            Arg::new("NotifyLine")
+            .long("notify-line")
+            .action(clap::ArgAction::SetTrue)
+            .help("Print a single key=value summary line at the end, for notifications and scripts")
+        )
+        .arg(
+            Arg::new("Retries")
+            .long("retries")
+            .num_args(1)
+            .value_name("N")
+            .help("Retry reads, copies and deletes that fail with a transient error (e.g. a timeout) up to N times (default 0)")
+            .value_parser(clap::value_parser!(u32))
+        )
+        .arg(
+            Arg::new("Quarantine")
+            .long("quarantine")
+            .value_name("DIR")
+            .help("Move images that are truncated or fail to decode into this directory")
+        )
+        .arg(
+            Arg::new("Exclude")
+            .long("exclude")
+            .value_name("GLOB")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_glob)
+            .help("Leave out files and directories matching GLOB, e.g. 'thumbnails' or '*.thumb.jpg' (repeatable)")
+        )
+        .arg(
+            Arg::new("Include")
+            .long("include")
+            .value_name("GLOB")
+            .action(clap::ArgAction::Append)
+            .value_parser(parse_glob)
+            .help("Only scan files matching GLOB, e.g. '2023/**' (repeatable)")
+        )
+        .arg(
+            Arg::new("MaxDepth")
+            .long("max-depth")
+            .value_name("N")
+            .value_parser(clap::value_parser!(usize))
+            .help("Only scan N levels of folders below each directory, 0 for just the directory itself")
+        )
+        .arg(
+            Arg::new("FollowSymlinks")
+            .long("follow-symlinks")
+            .action(clap::ArgAction::SetTrue)
+            .help("Scan the directories symlinks point to, each real directory once")
+        )
+        .arg(
+            Arg::new("RespectIgnore")
+            .long("respect-ignore")
+            .action(clap::ArgAction::SetTrue)
+            .help("Leave out the files and directories .gitignore, .ignore and the global git excludes list, and hidden ones")
+        )
+        .arg(
+            Arg::new("MinSize")
+            .long("min-size")
+            .value_name("SIZE")
+            .value_parser(parse_byte_size)
+            .help("Leave out images smaller than SIZE (e.g. 4096, 50K or 1M), such as icons")
+        )
+        .arg(
+            Arg::new("NoSort")
+            .long("no-sort")
+            .action(clap::ArgAction::SetTrue)
+            .help("Hash images in the order the file system lists them instead of sorting them by path")
+        )
+        .arg(
+            Arg::new("OnError")
+            .long("on-error")
+            .value_name("POLICY")
+            .value_parser(clap::value_parser!(OnError))
+            .help("What to do when a file can't be read, decoded, copied, moved or deleted: skip it (default), abort the run or retry it")
+        )
+        .arg(
+            Arg::new("TimeBudget")
+            .long("time-budget")
+            .num_args(1)
+            .value_name("DURATION")
+            .help("Stop starting new hashes, copies or deletes once this much time has passed (e.g. '90m', '2h')")
+            .value_parser(parse_time_budget)
+        )
+        .arg(
+            Arg::new("OlderThan")
+            .long("older-than")
+            .num_args(1)
+            .value_name("DURATION|DATE")
+            .help("Only consider files last modified before this age or date (e.g. '30d', '2023-06-01')")
+            .value_parser(parse_time_bound)
+        )
+        .arg(
+            Arg::new("NewerThan")
+            .long("newer-than")
+            .num_args(1)
+            .value_name("DURATION|DATE")
+            .help("Only consider files last modified after this age or date (e.g. '12h', '2024-01-01')")
+            .value_parser(parse_time_bound)
+        )
+        .arg(
+            Arg::new("MinGroupSize")
+            .long("min-group-size")
+            .num_args(1)
+            .value_name("N")
+            .help("Only act on duplicate groups with at least N members (default 2)")
+            .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("MinSavings")
+            .long("min-savings")
+            .value_name("SIZE")
+            .value_parser(parse_byte_size)
+            .help("Don't delete duplicates smaller than SIZE (e.g. 100K), they are still listed")
+        )
+        .arg(
+            Arg::new("MaxGroupSize")
+            .long("max-group-size")
+            .num_args(1)
+            .value_name("N")
+            .help("Leave duplicate groups with more than N members untouched, they are unlikely to be real duplicates")
+            .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("TrimBorders")
+            .long("trim-borders")
+            .action(clap::ArgAction::SetTrue)
+            .help("Crop uniform borders off images before hashing, to match scans and screenshots with different padding")
+        )
+        .arg(
+            Arg::new("Threshold")
+            .long("threshold")
+            .value_name("N")
+            .value_parser(clap::value_parser!(u32).range(0..=64))
+            .help("Group images whose hashes differ in at most N bits, default 0 (equal hashes only)")
+        )
+        .arg(
+            Arg::new("RobustMatch")
+            .long("robust-match")
+            .action(clap::ArgAction::SetTrue)
+            .help("Experimental: also match images whose centres hash alike, to catch reposts with added logos or captions")
+        )
+        .arg(
+            Arg::new("Consensus")
+            .long("consensus")
+            .value_name("BITS")
+            .num_args(0..=1)
+            .default_missing_value("4")
+            .value_parser(clap::value_parser!(u32).range(0..=64))
+            .help("Also compute an average hash of every image and only match images whose average hashes are at most BITS apart too (default 4)")
+        )
+        .arg(
+            Arg::new("HashBits")
+            .long("hash-bits")
+            .value_name("BITS")
+            .value_parser(clap::builder::PossibleValuesParser::new(["64", "144", "256"]).map(|bits| bits.parse::<u32>().unwrap()))
+            .help("Also compare a wider hash of 144 or 256 bits, so fewer different images are matched in large collections")
+        )
+        .arg(
+            Arg::new("NoExifRotate")
+            .long("no-exif-rotate")
+            .visible_alias("ignore-orientation")
+            .action(clap::ArgAction::SetTrue)
+            .help("Hash images as stored, without first turning them the way their EXIF orientation tag says")
+        )
+        .arg(
+            Arg::new("KeepLargestFile")
+            .long("keep-largest-file")
+            .action(clap::ArgAction::SetTrue)
+            .help("Keep the largest file on disk in each group, rather than the one with the largest dimensions")
+        )
+        .arg(
+            Arg::new("KeepOldest")
+            .long("keep-oldest")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("KeepNewest")
+            .help("Between images that tie on everything else, keep the one modified first rather than the first path")
+        )
+        .arg(
+            Arg::new("KeepNewest")
+            .long("keep-newest")
+            .action(clap::ArgAction::SetTrue)
+            .help("Between images that tie on everything else, keep the one modified last rather than the first path")
+        )
+        .arg(
+            Arg::new("Prefer")
+            .long("prefer")
+            .value_name("CRITERION")
+            .value_parser(clap::value_parser!(Prefer))
+            .conflicts_with("KeepLargestFile")
+            .help("What decides which image of a group is kept; ssim decodes every member to keep the least degraded one")
+        )
+        .arg(
+            Arg::new("KeepBest")
+            .long("keep-best")
+            .num_args(1)
+            .value_name("N")
+            .help("Keep the best N images of each group by the quality rules, instead of just one (default 1)")
+            .value_parser(clap::builder::RangedU64ValueParser::<usize>::new().range(1..))
+        )
+        .arg(
+            Arg::new("PreferName")
+            .long("prefer-name")
+            .num_args(1)
+            .value_name("REGEX")
+            .help("Always keep files whose name matches REGEX (e.g. '_final|_edited') over ones that don't")
+            .value_parser(|pattern: &str| Regex::new(pattern))
+        )
+        .arg(
+            Arg::new("PreferMetadata")
+            .long("prefer-metadata")
+            .action(clap::ArgAction::SetTrue)
+            .help("Between images of equal dimensions and JPEG quality, keep the one with an XMP sidecar or the most annotated EXIF data")
+        )
+        .arg(
+            Arg::new("Stream")
+            .long("stream")
+            .action(clap::ArgAction::SetTrue)
+            .help("Print likely duplicates as soon as they are hashed, before the final groups are known")
+        )
+        .arg(
+            Arg::new("Verbose")
+            .short('v')
+            .long("verbose")
+            .action(clap::ArgAction::SetTrue)
+            .help("Print every duplicate group and why its original was kept, every file skipped and every file changed")
+        )
+        .arg(
+            Arg::new("Quiet")
+            .short('q')
+            .long("quiet")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with("Verbose")
+            .help("Print no progress bars or status lines, only warnings, errors and the groups of a run without an action")
+        )
+        .arg(
+            Arg::new("JsonEvents")
+            .long("json-events")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all([
+                "Verbose", "Interactive", "Review", "ListUnique", "Cluster", "ReviewDir", "LoadHashes", "Stream", "NotifyLine",
+                "Baseline", "DiffActions", "Explain", "HashDistance",
+            ])
+            .help("Print one JSON object per line for every step of the run instead of progress bars and messages, for frontends")
+        )
+        .arg(
+            Arg::new("MaxAspectDiff")
+            .long("max-aspect-diff")
+            .num_args(1)
+            .value_name("RATIO")
+            .help("Don't match images whose width/height ratios differ by more than RATIO, e.g. an image and its crop")
+            .value_parser(clap::value_parser!(f64))
+        )
+        .arg(
+            Arg::new("RequireSameDimensions")
+            .long("require-same-dimensions")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only match images of exactly the same width and height, so resized copies are kept as well")
+        )
+        .arg(
+            Arg::new("PixelThreshold")
+            .long("pixel-threshold")
+            .num_args(1)
+            .value_name("0..255")
+            .help("Confirm each hash match by comparing small thumbnails, rejecting it if the mean pixel difference is above this")
+            .value_parser(clap::value_parser!(u8))
+        )
+        .arg(
+            Arg::new("LoadHashes")
+            .long("load-hashes")
+            .value_name("FILE")
+            .action(clap::ArgAction::Append)
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Review", "Across", "Cache", "Baseline", "DiffActions", "ListUnique", "Cluster"])
+            .help("Group the hashes stored in these --cache files instead of scanning, e.g. to compare libraries on different machines")
+        )
+        .arg(
+            Arg::new("Index")
+            .long("index")
+            .value_name("INDEX")
+            .value_parser(clap::value_parser!(HashIndex))
+            .requires("Cluster")
+            .help("Index --cluster looks up similar hashes in: map (exact matches only), bktree or lsh (fast at small distances, more memory). By default every pair is compared")
+        )
+        .arg(
+            Arg::new("ListUnique")
+            .long("list-unique")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Review", "Baseline", "DiffActions", "Cluster"])
+            .help("Only list the images that have no duplicate, one path per line")
+        )
+        .arg(
+            Arg::new("Cluster")
+            .long("cluster")
+            .value_name("DISTANCE")
+            .value_parser(clap::value_parser!(u32).range(0..=64))
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Review", "Baseline", "DiffActions"])
+            .help("Only report clusters of similar images, whose hashes differ by at most DISTANCE bits, and never delete")
+        )
+        .arg(
+            Arg::new("Like")
+            .long("like")
+            .value_name("FILE")
+            .conflicts_with_all(["Delete", "Hardlink", "Keep", "MoveTo", "Review", "Baseline", "DiffActions", "Cluster", "ListUnique", "LoadHashes", "ReviewDir"])
+            .help("Only list the scanned images that look like FILE, the most similar first, and never delete")
+        )
+        .arg(
+            Arg::new("LikeDistance")
+            .long("like-distance")
+            .value_name("BITS")
+            .value_parser(clap::value_parser!(u32).range(0..=64))
+            .requires("Like")
+            .help("How many bits the hashes of --like matches may differ by (default 10)")
+        )
+        .arg(
+            Arg::new("Dir")
+            .value_name("DIR")
+            .num_args(1..)
+            .conflicts_with("Across")
+            .help("The directories to scan, default the current one. Duplicates are found within and between them.")
+        )
+        .arg(
+            Arg::new("FromFile")
+            .long("from-file")
+            .value_name("LIST")
+            .conflicts_with_all(["Dir", "Across", "Reference", "FromStdin"])
+            .help("Hash the image files listed in LIST, one path per line, instead of scanning a directory")
+        )
+        .arg(
+            Arg::new("FromStdin")
+            .long("from-stdin")
+            .action(clap::ArgAction::SetTrue)
+            .conflicts_with_all(["Dir", "Across", "Reference", "Interactive"])
+            .help("Hash the image files listed on standard input, one path per line, instead of scanning a directory")
+        )
+        .arg(
+            Arg::new("Across")
+            .long("across")
+            .num_args(2..)
+            .value_name("DIR")
+            .help("Scan these directories instead of the current one, and only match images found in different ones")
+        )
+        .arg(
+            Arg::new("PerRootThreads")
+            .long("per-root-threads")
+            .action(clap::ArgAction::SetTrue)
+            .requires("Across")
+            .help("Give each --across directory its own hashing threads, for directories on separate drives")
+        )
+        .arg(
+            Arg::new("RemoveFrom")
+            .long("remove-from")
+            .num_args(1)
+            .value_name("DIR")
+            .requires("Across")
+            .help("With --across, always treat the copies in this directory as the duplicates")
+        )
+        .arg(
+            Arg::new("Reference")
+            .long("reference")
+            .num_args(1)
+            .value_name("DIR")
+            .conflicts_with("Across")
+            .help("Only act on scanned images that match one in the library DIR, which is never changed itself")
+        )
+        .arg(
+            Arg::new("OnePerDir")
+            .long("one-per-dir")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only group duplicates within the same folder, keeping the best image of each group per folder")
+        )
+        .arg(
+            Arg::new("ScanOutputs")
+            .long("scan-outputs")
+            .action(clap::ArgAction::SetTrue)
+            .help("Also scan the --keep, --quarantine and --review-dir directories and earlier runs' output")
+        )
+        .arg(
+            Arg::new("DedupReportThreshold")
+            .long("dedup-report-threshold")
+            .value_name("BITS")
+            .value_parser(clap::value_parser!(u32).range(0..=64))
+            .help("List duplicates within BITS of the match threshold as borderline, apart from the confident ones")
+        )
+        .arg(
+            Arg::new("ConfidentOnly")
+            .long("confident-only")
+            .action(clap::ArgAction::SetTrue)
+            .requires("DedupReportThreshold")
+            .help("Only delete or leave out the confident duplicates, borderline ones are treated as originals")
+        )
+        .arg(
+            Arg::new("ConfirmExact")
+            .long("confirm-exact")
+            .action(clap::ArgAction::SetTrue)
+            .help("Only act on duplicates that are byte for byte identical to their original, the rest are treated as originals")
+        )
+        .arg(
+            Arg::new("SortByDistance")
+            .long("sort-by-distance")
+            .action(clap::ArgAction::SetTrue)
+            .help("List each group's duplicates nearest to the original first, and the groups with the most distant duplicates last")
+        )
+        .arg(
+            Arg::new("Jsonl")
+            .long("jsonl")
+            .value_name("FILE")
+            .help("Write each duplicate group to FILE as one line of JSON as soon as it is settled, - for stdout")
+        )
+        .arg(
+            Arg::new("Log")
+            .long("log")
+            .value_name("FILE")
+            .help("Append every duplicate deleted, trashed or replaced by a hard link to FILE, with its size and original")
+        )
+        .arg(
+            Arg::new("Report")
+            .long("report")
+            .value_name("FILE")
+            .help("Write every duplicate group to FILE as a JSON array, before anything is deleted or copied")
+        )
+        .arg(
+            Arg::new("SaveGroups")
+            .long("save-groups")
+            .num_args(1)
+            .value_name("FILE")
+            .help("Save a snapshot of the duplicate groups found to FILE, for use with --baseline")
+        )
+        .arg(
+            Arg::new("Baseline")
+            .long("baseline")
+            .num_args(1)
+            .value_name("FILE")
+            .help("Only report duplicates that are new since the snapshot in FILE was saved (nothing is deleted or copied)")
+        )
+        .arg(
+            Arg::new("DiffActions")
+            .long("diff-actions")
+            .num_args(1)
+            .value_name("FILE")
+            .conflicts_with("Baseline")
+            .help("Report how the files this run would remove differ from the duplicates in the snapshot in FILE (nothing is deleted or copied)")
+        )
+        .arg(
+            Arg::new("HashDistance")
+            .long("hash-distance")
+            .num_args(2)
+            .value_names(["HASH", "HASH"])
+            .help("Print the number of bits that differ between two hex hashes (as stored in caches and snapshots) and exit")
+        )
+        .arg(
+            Arg::new("Explain")
+            .long("explain")
+            .num_args(2)
+            .value_names(["FILE", "FILE"])
+            .help("Show the hashes of two images as grids, marking the bits that differ, and exit")
+        )
+        .arg(
+            Arg::new("Cache")
+            .long("cache")
+            .num_args(1)
+            .value_name("FILE")
+            .help("Store hashes in FILE and reuse them for files that haven't changed since")
+        )
+        .arg(
+            Arg::new("NoCache")
+            .long("no-cache")
+            .action(clap::ArgAction::SetTrue)
+            .help("Ignore --cache for this run, hashing every image afresh and leaving the cache untouched")
+        )
+        .arg(
+            Arg::new("Resume")
+            .long("resume")
+            .value_name("FILE")
+            .help("Record the scan and the hashes made in FILE as the run goes, and pick an interrupted run up from it")
+        )
+        .arg(
+            Arg::new("VerifyCache")
+            .long("verify-cache")
+            .num_args(0..=1)
+            .value_name("SAMPLE")
+            .default_missing_value("20")
+            .requires("Cache")
+            .help("Rehash a random sample of cached files to check the cache is still accurate (default 20)")
             .value_parser(clap::value_parser!(usize))
         )
         .about(
             "A fast utility for removing duplicate image files with perceptual hashing."
-        )
+        );
+
+    // Only builds with the "zip" feature can look inside archives
+    #[cfg(feature = "zip")]
+    let command = command.arg(
+        Arg::new("DedupWithinArchives")
+        .long("dedup-within-archives")
+        .action(clap::ArgAction::SetTrue)
+        .help("Also hash the images inside .zip archives, reporting matches without ever changing the archives")
+    );
+
+    command
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borderline_matches_are_judged_by_the_distance_they_were_matched_at() {
+        let hashed = |path: &str, hash, side| ImageInfo {
+            path: path.to_string(), hash, dimensions: (side, side), size: 10, root: 0, jpeg_quality: None, metadata: 0, modified: None,
+        };
+        let pairs = [hashed("first", 0b0000, 100), hashed("larger", 0b0011, 200), hashed("late", 0b1100, 100)]
+            .into_iter().map(|image| ((image.hash, ()), image));
+        let rules = QualityRules { prefer: Prefer::Resolution, preferred_name: None, remove_from: None, reference: None, keep_age: None };
+        let groups = find_duplicates(pairs, 2, &rules, |_, _| true, &NoProgress);
+        let group = &groups[0];
+        assert_eq!(group.original.path, "larger");
+        // Both were matched within 1 bit of the threshold, though "late" is 4 bits from the new original
+        let borderline = |above| group.duplicates.iter().filter(|dup| is_borderline(group, dup, Some(above))).count();
+        assert_eq!(borderline(1), 1);
+        assert_eq!(borderline(2), 0);
+        assert!(!group.duplicates.iter().any(|dup| is_borderline(group, dup, None)));
+    }
+
+    #[test]
+    fn thread_counts_are_whole_numbers_or_auto() {
+        assert_eq!(parse_thread_count("0"), Ok(0));
+        assert_eq!(parse_thread_count("auto"), Ok(0));
+        assert_eq!(parse_thread_count(" AUTO "), Ok(0));
+        assert_eq!(parse_thread_count("4"), Ok(4));
+        for arg in ["", "four", "-1", "1.5", "4 threads"] {
+            assert!(parse_thread_count(arg).is_err(), "'{}' should be refused", arg);
+        }
+    }
+
+    // A fresh directory for one test, removed again by the test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dedup-main-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn output_dirs_inside_a_scanned_root_are_left_out() {
+        let root = scratch_dir("outputs");
+        let (inside, missing) = (root.join("kept"), root.join("not-yet"));
+        fs::create_dir(&inside).unwrap();
+        let (inside, missing) = (inside.to_str().unwrap(), missing.to_str().unwrap());
+        let roots = [root.clone()];
+
+        let excluded = excluded_output_dirs(&[("--keep", inside), ("--quarantine", missing)], &roots, false).unwrap();
+        assert_eq!(excluded, [fs::canonicalize(inside).unwrap()]);
+        assert!(excluded_output_dirs(&[("--keep", inside)], &roots, true).unwrap().is_empty());
+        // An output directory around the scanned one would be scanned along with it
+        let outer = root.parent().unwrap().to_str().unwrap();
+        assert!(excluded_output_dirs(&[("--keep", outer)], &[PathBuf::from(inside)], false).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_directory_without_images_fails_the_run() {
+        let root = scratch_dir("no-images");
+        let options = ScanOptions {
+            all_formats: false, archives: false, excluded: vec![], skip_marked: true, exclude: vec![], include: vec![], max_depth: None,
+            follow_symlinks: false, respect_ignore: false,
+        };
+        let found = |root: &Path| get_images_in_dir(root, &options, &RunContext::default(), &NoProgress).unwrap().len();
+        let roots = [root.clone()];
 
+        assert_eq!(found(&root), 0);
+        let error = require_images(&[], &roots, false).unwrap_err().to_string();
+        assert_eq!(error, format!("no supported image files found in '{}'", root.display()));
+        fs::write(root.join("notes.txt"), "not an image").unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+        assert_eq!(found(&root), 0);
+        assert!(require_images(&[], &roots, true).unwrap_err().to_string().contains("the file list"));
+        assert!(require_images(&[(String::from("a.jpg"), 1)], &roots, false).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn the_action_follows_the_flags_given() {
+        let action_of = |args: &[&str]| {
+            let m = cli().try_get_matches_from(std::iter::once("dedup").chain(args.iter().copied())).unwrap();
+            format!("{:?}", chosen_action(&m))
+        };
+        assert_eq!(action_of(&["dir"]), "List");
+        assert_eq!(action_of(&["dir", "--dry-run"]), "List");
+        assert_eq!(action_of(&["dir", "--group-only"]), "List");
+        assert_eq!(action_of(&["dir", "--delete"]), "Delete");
+        assert_eq!(action_of(&["dir", "--delete", "--trash"]), "Delete");
+        assert_eq!(action_of(&["dir", "--hardlink"]), "Hardlink");
+        assert_eq!(action_of(&["dir", "--keep"]), r#"Keep("target")"#);
+        assert_eq!(action_of(&["dir", "--keep", "out"]), r#"Keep("out")"#);
+        assert_eq!(action_of(&["dir", "--move-to", "out"]), r#"MoveTo("out")"#);
+        for clash in [["--delete", "--keep"], ["--hardlink", "--delete"], ["--move-to=out", "--keep"], ["--group-only", "--delete"]] {
+            assert!(cli().try_get_matches_from(["dedup", "dir", clash[0], clash[1]]).is_err(), "{:?} should be refused", clash);
+        }
+    }
+}